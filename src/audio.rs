@@ -1,8 +1,18 @@
+use crate::downmix::{downmix, Downmix};
+use crate::profiles::Profile;
+use crate::resample::ResampleQuality;
+use crate::ring_buffer::OverflowPolicy;
 use crate::settings::CommandArguments;
-use crate::speech_to_text::{StreamFinishProperties, SttStreamingState};
+use crate::speech_to_text::{transcribe, StreamFinishProperties, SttStreamingState};
+use crate::stt_engine::{SttEngine, Transcript};
+use anyhow::{anyhow, bail};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Device, Host, Stream, SupportedStreamConfig};
-use log::{debug, error};
+use cpal::{
+    Device, FromSample, Host, Sample, SampleFormat, SizedSample, Stream, StreamConfig,
+    SupportedStreamConfig,
+};
+use log::{debug, error, info};
+use serde::Serialize;
 use std::sync::Arc;
 
 pub fn new_host() -> Host {
@@ -12,44 +22,243 @@ pub fn new_host() -> Host {
     return host;
 }
 
+/// Finds an input device by name in `host` first, then in every other available host
+/// (e.g. ASIO), so that every device `list-devices` shows can be picked
 pub fn new_input_device(
     host: &Host,
     desired_input_device: &String,
 ) -> Result<Device, anyhow::Error> {
-    let device = if desired_input_device == "default" {
-        host.default_input_device()
-    } else {
-        host.input_devices()?.find(|x| {
-            x.name()
-                .map(|y| y == *desired_input_device)
-                .unwrap_or(false)
+    if desired_input_device == "default" {
+        return host
+            .default_input_device()
+            .ok_or(anyhow!("failed to find default input device"));
+    }
+
+    if let Some(device) = find_input_device(host, desired_input_device) {
+        return Ok(device);
+    }
+    for host_id in cpal::available_hosts() {
+        if host_id == host.id() {
+            continue;
+        }
+        let other_host = match cpal::host_from_id(host_id) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        if let Some(device) = find_input_device(&other_host, desired_input_device) {
+            info!(
+                "found input device '{}' on host {}",
+                desired_input_device,
+                host_id.name()
+            );
+            return Ok(device);
+        }
+    }
+
+    let names: Vec<String> = list_input_devices()
+        .into_iter()
+        .flat_map(|x| x.input_devices)
+        .map(|x| x.name)
+        .collect();
+    bail!(
+        "failed to find input device '{}', closest matches: {:?}",
+        desired_input_device,
+        closest_names(desired_input_device, &names, 3)
+    );
+}
+
+fn find_input_device(host: &Host, name: &str) -> Option<Device> {
+    return host
+        .input_devices()
+        .ok()?
+        .find(|x| x.name().map(|y| y == name).unwrap_or(false));
+}
+
+/// `count` names that are the most similar to `desired`
+fn closest_names<'a>(desired: &str, names: &'a [String], count: usize) -> Vec<&'a str> {
+    let desired = desired.to_lowercase();
+    let mut scored: Vec<(f64, &str)> = names
+        .iter()
+        .map(|name| {
+            let lowercase = name.to_lowercase();
+            let mut score = strsim::normalized_levenshtein(&desired, &lowercase);
+            // partial names like "focusrite" should still find "Focusrite USB Audio"
+            if lowercase.contains(&desired) || desired.contains(&lowercase) {
+                score += 1.0;
+            }
+            (score, name.as_str())
         })
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    return scored.into_iter().take(count).map(|(_, x)| x).collect();
+}
+
+#[derive(Serialize, Debug)]
+pub struct StreamConfigInfo {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub sample_format: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct InputDeviceInfo {
+    pub name: String,
+    pub is_default: bool,
+    pub configs: Vec<StreamConfigInfo>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HostInfo {
+    pub name: String,
+    pub input_devices: Vec<InputDeviceInfo>,
+}
+
+/// Every available host with its input devices and their supported stream configs
+pub fn list_input_devices() -> Vec<HostInfo> {
+    let mut hosts = Vec::new();
+    for host_id in cpal::available_hosts() {
+        let host = match cpal::host_from_id(host_id) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("host {} unavailable: {}", host_id.name(), e);
+                continue;
+            }
+        };
+        let default_name = host.default_input_device().and_then(|x| x.name().ok());
+
+        let devices = match host.input_devices() {
+            Ok(x) => x,
+            Err(e) => {
+                error!("could not list input devices of {}: {}", host_id.name(), e);
+                continue;
+            }
+        };
+
+        let input_devices = devices
+            .map(|device| {
+                let name = device.name().unwrap_or("NO_NAME_FOUND".to_string());
+                let configs = device
+                    .supported_input_configs()
+                    .map(|configs| {
+                        configs
+                            .map(|x| StreamConfigInfo {
+                                channels: x.channels(),
+                                min_sample_rate: x.min_sample_rate().0,
+                                max_sample_rate: x.max_sample_rate().0,
+                                sample_format: x.sample_format().to_string(),
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                InputDeviceInfo {
+                    is_default: default_name.as_ref() == Some(&name),
+                    name,
+                    configs,
+                }
+            })
+            .collect();
+
+        hosts.push(HostInfo {
+            name: host_id.name().to_string(),
+            input_devices,
+        });
     }
-    .expect("failed to find input device");
-    return Ok(device);
+
+    return hosts;
+}
+
+/// Converts any cpal sample type to f32 normalized to `-1.0..1.0`\
+/// `output` is cleared first so that it can be reused between callbacks
+pub fn samples_to_f32<T>(data: &[T], output: &mut Vec<f32>)
+where
+    T: Sample,
+    f32: FromSample<T>,
+{
+    output.clear();
+    output.extend(data.iter().map(|sample| sample.to_sample::<f32>()));
 }
 
 // -----------------------------------------------------------------------------
 
 pub struct VoxStream {
     pub stt: Arc<SttStreamingState>,
-    pub audio_in: Stream,
+    pub engine: Arc<dyn SttEngine>,
+    /// `None` when the source is not a live input device
+    pub audio_in: Option<Stream>,
+    pub channels: u16,
+    pub downmix: Downmix,
+    pub sample_rate: u32,
+    pub resample_quality: ResampleQuality,
 }
 
 impl VoxStream {
-    /// this will drop audio_in
-    pub fn finish_stream(self, initial_prompt: &str, stereo_to_string_conversions: u16) -> String {
+    /// moves captured audio out of the real-time buffer
+    pub fn drain(&self) {
+        self.stt.drain();
+    }
+
+    /// this will drop audio_in\
+    /// returns `None` when no speech was captured
+    pub fn finish_stream(self, profile: &Profile) -> Option<Transcript> {
         debug!("[VoxStream] finishing stream");
+        let properties = self.finish_properties(profile);
         drop(self.audio_in);
         let stream = Arc::into_inner(self.stt).expect("SttStreamingState required");
 
         return stream
-            .finish_stream(StreamFinishProperties {
-                verbose: false,
-                initial_prompt,
-                halver_count: stereo_to_string_conversions,
-            })
-            .unwrap_or("".to_string());
+            .finish_stream(properties)
+            .unwrap_or(Some(Transcript::default()));
+    }
+
+    /// takes everything captured so far, downmixed to mono
+    pub fn take_mono_audio(&self) -> Vec<f32> {
+        return downmix(&self.stt.take_audio(), self.channels, self.downmix);
+    }
+
+    /// Copies the last `window_ms` of the utterance without taking it, for partial transcripts\
+    /// also returns the position the copy ends at, see [Self::discard_audio]
+    pub fn peek_audio(&self, window_ms: u64) -> (Vec<f32>, usize) {
+        // whole frames so that channels are not split up
+        let frames = self.sample_rate as u64 * window_ms / 1000;
+        return self
+            .stt
+            .peek_audio(frames as usize * self.channels as usize);
+    }
+
+    /// drops the utterance up to `end` from [Self::peek_audio], e.g. after a command fired early
+    pub fn discard_audio(&self, end: usize) {
+        self.stt.discard_audio(end);
+    }
+
+    /// for streams that stay open, the ring buffer contents become the pre-roll
+    pub fn start_utterance(&self) {
+        debug!("[VoxStream] starting utterance");
+        self.stt.start_utterance();
+    }
+
+    /// transcribes the current utterance while audio_in keeps capturing\
+    /// returns `None` when no speech was captured
+    pub fn finish_utterance(&self, profile: &Profile) -> Option<Transcript> {
+        debug!("[VoxStream] finishing utterance");
+        return self
+            .stt
+            .finish_utterance(self.finish_properties(profile))
+            .unwrap_or(Some(Transcript::default()));
+    }
+
+    fn finish_properties<'a>(&self, profile: &'a Profile) -> StreamFinishProperties<'a> {
+        return StreamFinishProperties {
+            engine: self.engine.clone(),
+            whisper: &profile.whisper,
+            channels: self.channels,
+            downmix: self.downmix,
+            sample_rate: self.sample_rate,
+            resample_quality: self.resample_quality,
+            trim: &profile.trim,
+        };
     }
 }
 /// my inexperience will most likely bite me for this
@@ -57,18 +266,52 @@ unsafe impl Send for VoxStream {}
 
 // -----------------------------------------------------------------------------
 
+/// Anything that can feed audio into a [VoxStream]
+pub trait AudioSource {
+    /// creates a new stream that starts receiving audio from this source\
+    /// `start_play` only matters for live sources
+    fn new_stream(&self, start_play: bool) -> VoxStream;
+}
+
+// -----------------------------------------------------------------------------
+
 pub struct VoxAudio {
     // host: Host,
     pub input_device: Device,
+    pub engine: Arc<dyn SttEngine>,
+    pub downmix: Downmix,
+    pub resample_quality: ResampleQuality,
+    /// how much audio (in milliseconds) the callback can buffer between drains
+    pub capture_buffer_ms: u64,
+    pub overflow_policy: OverflowPolicy,
+    /// audio (in milliseconds) from before the record key was pressed to keep, 0 to disable
+    pub pre_roll_ms: u64,
 }
 
 impl VoxAudio {
-    pub fn new(args: &CommandArguments) -> Self {
+    pub fn new(args: &CommandArguments, engine: Arc<dyn SttEngine>) -> Self {
         let host = new_host();
         let input_device =
             new_input_device(&host, &args.audio_in).expect("Audio input device needed");
 
-        return Self { input_device };
+        let downmix = Downmix::new(args.input_channel);
+        let channels = input_device
+            .default_input_config()
+            .expect("failed to get default input supported stream config")
+            .channels();
+        downmix
+            .validate(channels)
+            .expect("valid input channel required");
+
+        return Self {
+            input_device,
+            engine,
+            downmix,
+            resample_quality: args.resample_quality,
+            capture_buffer_ms: args.capture_buffer_ms,
+            overflow_policy: args.overflow_policy,
+            pre_roll_ms: args.pre_roll_ms,
+        };
     }
 
     pub fn input_stream_config(&self) -> SupportedStreamConfig {
@@ -79,12 +322,42 @@ impl VoxAudio {
         return config;
     }
 
-    pub fn new_stream(&self, start_play: bool) -> VoxStream {
-        debug!("[VoxAudio] creating new stream");
-        let stt_stream = Arc::new(SttStreamingState::new());
+    /// Transcribes mono audio captured from this device, see [VoxStream::take_mono_audio]\
+    /// returns `None` when there is no speech in it
+    pub fn transcribe_mono(&self, audio: Vec<f32>, profile: &Profile) -> Option<Transcript> {
+        let properties = StreamFinishProperties {
+            engine: self.engine.clone(),
+            whisper: &profile.whisper,
+            channels: 1,
+            downmix: self.downmix,
+            sample_rate: self.input_stream_config().sample_rate().0,
+            resample_quality: self.resample_quality,
+            trim: &profile.trim,
+        };
+        return transcribe(audio, properties).unwrap_or(Some(Transcript::default()));
+    }
+
+    /// Opens a stream that is meant to stay open, its buffer only ever holds the last
+    /// `pre_roll_ms` of audio which becomes the start of the next utterance
+    pub fn new_pre_roll_stream(&self) -> VoxStream {
+        debug!("[VoxAudio] creating new pre-roll stream");
+        return self.new_stream_with_buffer(self.pre_roll_ms, OverflowPolicy::DropOldest, true);
+    }
+
+    fn new_stream_with_buffer(
+        &self,
+        buffer_ms: u64,
+        overflow_policy: OverflowPolicy,
+        start_play: bool,
+    ) -> VoxStream {
+        let input_config = self.input_stream_config();
+        // whole frames so that overflowing never splits up channels
+        let frames = input_config.sample_rate().0 as u64 * buffer_ms / 1000;
+        let capacity = frames as usize * input_config.channels() as usize;
+        let stt_stream = Arc::new(SttStreamingState::new(capacity, overflow_policy));
 
         let data_handler_stream = stt_stream.clone();
-        let data_handler = move |data: &[f32], _: &_| data_handler_stream.feed_audio(data.to_vec());
+        let data_handler = move |data: &[f32]| data_handler_stream.feed_audio(data);
 
         let input_stream: Stream = self
             .new_input_stream(data_handler)
@@ -97,22 +370,292 @@ impl VoxAudio {
 
         return VoxStream {
             stt: stt_stream,
-            audio_in: input_stream,
+            engine: self.engine.clone(),
+            audio_in: Some(input_stream),
+            channels: input_config.channels(),
+            downmix: self.downmix,
+            sample_rate: input_config.sample_rate().0,
+            resample_quality: self.resample_quality,
         };
     }
 
+    /// builds an input stream in the device's native sample format,
+    /// `data_handler` always receives normalized f32 samples
     fn new_input_stream(
         &self,
-        data_handler: impl FnMut(&[f32], &cpal::InputCallbackInfo) + std::marker::Send + 'static,
+        mut data_handler: impl FnMut(&[f32]) + std::marker::Send + 'static,
     ) -> Result<Stream, anyhow::Error> {
-        let config: cpal::StreamConfig = self.input_stream_config().into();
-        let input_device = &self.input_device;
+        let supported_config = self.input_stream_config();
+        let sample_format = supported_config.sample_format();
+        let config: StreamConfig = supported_config.into();
+        debug!("[VoxAudio] building input stream with {:?}", sample_format);
 
-        let err_fn = move |err| {
-            error!("an error occurred on stream: {}", err);
+        let stream = match sample_format {
+            SampleFormat::F32 => self.input_device.build_input_stream(
+                &config,
+                move |data: &[f32], _: &_| data_handler(data),
+                stream_error,
+                None,
+            )?,
+            SampleFormat::F64 => self.new_converting_stream::<f64>(&config, data_handler)?,
+            SampleFormat::I8 => self.new_converting_stream::<i8>(&config, data_handler)?,
+            SampleFormat::I16 => self.new_converting_stream::<i16>(&config, data_handler)?,
+            SampleFormat::I32 => self.new_converting_stream::<i32>(&config, data_handler)?,
+            SampleFormat::I64 => self.new_converting_stream::<i64>(&config, data_handler)?,
+            SampleFormat::U8 => self.new_converting_stream::<u8>(&config, data_handler)?,
+            SampleFormat::U16 => self.new_converting_stream::<u16>(&config, data_handler)?,
+            SampleFormat::U32 => self.new_converting_stream::<u32>(&config, data_handler)?,
+            SampleFormat::U64 => self.new_converting_stream::<u64>(&config, data_handler)?,
+            format => bail!("unsupported sample format {:?}", format),
         };
-
-        let stream = input_device.build_input_stream(&config, data_handler, err_fn, None)?;
         return Ok(stream);
     }
+
+    fn new_converting_stream<T>(
+        &self,
+        config: &StreamConfig,
+        mut data_handler: impl FnMut(&[f32]) + std::marker::Send + 'static,
+    ) -> Result<Stream, cpal::BuildStreamError>
+    where
+        T: SizedSample,
+        f32: FromSample<T>,
+    {
+        // reused between callbacks so that converting does not allocate every time
+        let mut converted: Vec<f32> = Vec::new();
+        return self.input_device.build_input_stream(
+            config,
+            move |data: &[T], _: &_| {
+                samples_to_f32(data, &mut converted);
+                data_handler(&converted);
+            },
+            stream_error,
+            None,
+        );
+    }
+}
+
+impl AudioSource for VoxAudio {
+    fn new_stream(&self, start_play: bool) -> VoxStream {
+        debug!("[VoxAudio] creating new stream");
+        return self.new_stream_with_buffer(
+            self.capture_buffer_ms,
+            self.overflow_policy,
+            start_play,
+        );
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Reads a whole WAV file into memory, useful for testing without a microphone
+pub struct WavSource {
+    audio: Vec<f32>,
+    engine: Arc<dyn SttEngine>,
+    channels: u16,
+    sample_rate: u32,
+    downmix: Downmix,
+    resample_quality: ResampleQuality,
+}
+
+impl WavSource {
+    pub fn new(
+        path: &str,
+        args: &CommandArguments,
+        engine: Arc<dyn SttEngine>,
+    ) -> Result<Self, anyhow::Error> {
+        let reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        debug!("[WavSource] reading {} with {:?}", path, spec);
+
+        let audio = match spec.sample_format {
+            hound::SampleFormat::Float => reader.into_samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                // integer samples are normalized by their bit depth
+                let scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .into_samples::<i32>()
+                    .map(|sample| sample.map(|x| x as f32 / scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+
+        let downmix = Downmix::new(args.input_channel);
+        downmix.validate(spec.channels)?;
+
+        return Ok(Self {
+            audio,
+            engine,
+            channels: spec.channels,
+            sample_rate: spec.sample_rate,
+            downmix,
+            resample_quality: args.resample_quality,
+        });
+    }
+}
+
+impl AudioSource for WavSource {
+    fn new_stream(&self, _start_play: bool) -> VoxStream {
+        debug!("[WavSource] creating new stream");
+        let stt_stream = Arc::new(SttStreamingState::new(
+            self.audio.len(),
+            OverflowPolicy::Stop,
+        ));
+        stt_stream.feed_audio(&self.audio);
+
+        return VoxStream {
+            stt: stt_stream,
+            engine: self.engine.clone(),
+            audio_in: None,
+            channels: self.channels,
+            downmix: self.downmix,
+            sample_rate: self.sample_rate,
+            resample_quality: self.resample_quality,
+        };
+    }
+}
+
+fn stream_error(err: cpal::StreamError) {
+    error!("an error occurred on stream: {}", err);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// converts `[min, mid, max]` and checks they land on -1.0, 0.0 and about 1.0
+    fn check_range<T>(min: T, mid: T, max: T)
+    where
+        T: Sample,
+        f32: FromSample<T>,
+    {
+        let mut output = vec![42.0; 8];
+        samples_to_f32(&[min, mid, max], &mut output);
+        assert_eq!(output.len(), 3);
+        assert_eq!(output[0], -1.0);
+        assert_eq!(output[1], 0.0);
+        assert!((output[2] - 1.0).abs() < 0.01, "max became {}", output[2]);
+    }
+
+    #[test]
+    fn converts_i8() {
+        check_range(i8::MIN, 0, i8::MAX);
+    }
+
+    #[test]
+    fn converts_i16() {
+        check_range(i16::MIN, 0, i16::MAX);
+    }
+
+    #[test]
+    fn converts_i32() {
+        check_range(i32::MIN, 0, i32::MAX);
+    }
+
+    #[test]
+    fn converts_i64() {
+        check_range(i64::MIN, 0, i64::MAX);
+    }
+
+    #[test]
+    fn converts_u8() {
+        check_range(u8::MIN, 1 << 7, u8::MAX);
+    }
+
+    #[test]
+    fn converts_u16() {
+        check_range(u16::MIN, 1 << 15, u16::MAX);
+    }
+
+    #[test]
+    fn converts_u32() {
+        check_range(u32::MIN, 1 << 31, u32::MAX);
+    }
+
+    #[test]
+    fn converts_u64() {
+        check_range(u64::MIN, 1 << 63, u64::MAX);
+    }
+
+    #[test]
+    fn converts_f64() {
+        check_range(-1.0_f64, 0.0, 1.0);
+    }
+
+    #[test]
+    fn output_is_cleared_and_reused() {
+        let mut output = Vec::new();
+        samples_to_f32(&[0_i16; 64], &mut output);
+        let capacity = output.capacity();
+        let pointer = output.as_ptr();
+
+        samples_to_f32(&[i16::MIN, i16::MAX], &mut output);
+        assert_eq!(output.len(), 2);
+        assert_eq!(output[0], -1.0);
+        // smaller callbacks do not allocate again
+        assert_eq!(output.capacity(), capacity);
+        assert_eq!(output.as_ptr(), pointer);
+    }
+
+    #[test]
+    fn closest_names_prefer_partial_names() {
+        let names = [
+            "Microphone (Realtek Audio)".to_string(),
+            "Focusrite USB ASIO".to_string(),
+            "Line In (Focusrite USB Audio)".to_string(),
+        ];
+        let closest = closest_names("focusrite usb", &names, 2);
+        assert_eq!(closest.len(), 2);
+        assert!(
+            closest.iter().all(|x| x.contains("Focusrite")),
+            "{:?}",
+            closest
+        );
+        assert!(closest_names("anything", &[], 3).is_empty());
+    }
+
+    /// stereo stream that is fed by hand instead of by an input device
+    fn stereo_stream() -> VoxStream {
+        return VoxStream {
+            stt: Arc::new(SttStreamingState::new(1000, OverflowPolicy::Stop)),
+            // nothing is transcribed here
+            engine: Arc::new(crate::speech_to_text::WhisperEngine),
+            audio_in: None,
+            channels: 2,
+            downmix: Downmix::Average,
+            sample_rate: 1000,
+            resample_quality: ResampleQuality::Linear,
+        };
+    }
+
+    /// `count` stereo frames, both channels of frame `x` are `first + x`
+    fn frames(first: usize, count: usize) -> Vec<f32> {
+        return (first..first + count)
+            .flat_map(|x| [x as f32, x as f32])
+            .collect();
+    }
+
+    #[test]
+    fn peek_copies_the_last_window() {
+        let stream = stereo_stream();
+        stream.stt.feed_audio(&frames(0, 100));
+
+        // 20 ms at 1 kHz are 20 frames of two samples
+        let (window, end) = stream.peek_audio(20);
+        assert_eq!(window, frames(80, 20));
+        assert_eq!(end, 200);
+        // peeking takes nothing
+        assert_eq!(stream.take_mono_audio().len(), 100);
+    }
+
+    #[test]
+    fn discarding_keeps_audio_captured_after_the_peek() {
+        let stream = stereo_stream();
+        stream.stt.feed_audio(&frames(0, 100));
+        let (_, end) = stream.peek_audio(20);
+        stream.stt.feed_audio(&frames(100, 30));
+
+        stream.discard_audio(end);
+        let expected: Vec<f32> = (100..130).map(|x| x as f32).collect();
+        assert_eq!(stream.take_mono_audio(), expected);
+    }
 }
@@ -0,0 +1,126 @@
+/// Interleaved multi-channel to mono conversion
+///
+/// cpal hands us interleaved frames (`[l, r, l, r, ...]` for stereo), with however many
+/// channels the input device has. whisper only wants a single channel.
+use anyhow::bail;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Downmix {
+    /// Average every channel of a frame
+    #[default]
+    Average,
+    /// Only keep one channel (1-based), for audio interfaces where the mic is on a single input
+    Channel(u16),
+}
+
+impl Downmix {
+    pub fn new(input_channel: Option<u16>) -> Self {
+        return match input_channel {
+            None => Downmix::Average,
+            Some(channel) => Downmix::Channel(channel),
+        };
+    }
+
+    /// Checks that the selected channel exists on a device with `channels` channels
+    pub fn validate(&self, channels: u16) -> anyhow::Result<()> {
+        if let Downmix::Channel(channel) = *self {
+            if channel == 0 || channel > channels {
+                bail!(
+                    "input channel {} does not exist, device has channels 1 to {}",
+                    channel,
+                    channels
+                );
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Converts interleaved `audio` with `channels` channels to mono\
+/// an incomplete frame at the end is discarded
+pub fn downmix(audio: &[f32], channels: u16, mode: Downmix) -> Vec<f32> {
+    let channels = channels as usize;
+    if channels <= 1 {
+        return audio.to_vec();
+    }
+
+    let frames = audio.chunks_exact(channels);
+    return match mode {
+        Downmix::Average => frames
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect(),
+        Downmix::Channel(channel) => {
+            let index = (channel as usize).clamp(1, channels) - 1;
+            frames.map(|frame| frame[index]).collect()
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL_COUNTS: [u16; 5] = [1, 2, 4, 6, 8];
+    const FRAMES: usize = 16;
+
+    /// sample `frame * 100 + channel`, so every sample says where it came from
+    fn interleaved(channels: u16) -> Vec<f32> {
+        return (0..FRAMES)
+            .flat_map(|frame| {
+                (0..channels).map(move |channel| (frame * 100) as f32 + channel as f32)
+            })
+            .collect();
+    }
+
+    #[test]
+    fn average_of_every_channel() {
+        for channels in CHANNEL_COUNTS {
+            let mono = downmix(&interleaved(channels), channels, Downmix::Average);
+            assert_eq!(mono.len(), FRAMES, "{} channels", channels);
+            // the mean of 0..channels is (channels - 1) / 2
+            let offset = (channels - 1) as f32 / 2.0;
+            for (frame, sample) in mono.iter().enumerate() {
+                assert_eq!(
+                    *sample,
+                    (frame * 100) as f32 + offset,
+                    "{} channels",
+                    channels
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn single_channel() {
+        for channels in CHANNEL_COUNTS {
+            for channel in 1..=channels {
+                let mono = downmix(&interleaved(channels), channels, Downmix::Channel(channel));
+                let expected: Vec<f32> = (0..FRAMES)
+                    .map(|frame| (frame * 100) as f32 + (channel - 1) as f32)
+                    .collect();
+                assert_eq!(mono, expected, "channel {} of {}", channel, channels);
+            }
+        }
+    }
+
+    #[test]
+    fn incomplete_trailing_frame_is_dropped() {
+        for channels in CHANNEL_COUNTS.into_iter().filter(|x| *x > 1) {
+            let mut audio = interleaved(channels);
+            audio.extend(vec![1000.0; channels as usize - 1]);
+            assert_eq!(downmix(&audio, channels, Downmix::Average).len(), FRAMES);
+            assert_eq!(downmix(&audio, channels, Downmix::Channel(1)).len(), FRAMES);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_missing_channels() {
+        for channels in CHANNEL_COUNTS {
+            assert!(Downmix::Average.validate(channels).is_ok());
+            assert!(Downmix::Channel(0).validate(channels).is_err());
+            assert!(Downmix::Channel(1).validate(channels).is_ok());
+            assert!(Downmix::Channel(channels).validate(channels).is_ok());
+            assert!(Downmix::Channel(channels + 1).validate(channels).is_err());
+        }
+    }
+}
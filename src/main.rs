@@ -1,28 +1,58 @@
-use crate::{audio::VoxStream, speech_to_text::PROMPT_REGEX};
+use crate::{
+    audio::{AudioSource, VoxStream},
+    settings::{CommandArguments, SubCommand},
+    stt_engine::Transcript,
+};
 use cpal::traits::DeviceTrait;
-use log::{info, warn};
+use log::{debug, info, warn};
 use std::{
-    sync::{Arc, Mutex},
-    thread::sleep,
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, sleep},
     time::Duration,
 };
 
 mod audio;
+mod downmix;
 mod inputbot_patch;
+mod matching;
+mod model;
+mod phonetic;
 mod profiles;
+mod resample;
+mod ring_buffer;
 mod settings;
 mod speech_to_text;
+mod streaming;
+mod stt_engine;
+mod subcommands;
+mod trim;
+mod vad;
+mod validator;
+mod vocabulary;
+mod wake_word;
 
 pub fn main() {
     let args = settings::CommandArguments::new();
     settings::init();
-    speech_to_text::load(&args.model_path);
 
-    let vox_audio = Arc::new(audio::VoxAudio::new(&args));
+    match &args.command {
+        Some(SubCommand::Transcribe { file }) => return subcommands::transcribe(&args, file),
+        Some(SubCommand::ListDevices { json }) => return subcommands::list_devices(*json),
+        Some(SubCommand::Model { command }) => return subcommands::model(&args, command),
+        Some(SubCommand::PhoneticKeys) => return subcommands::phonetic_keys(&args),
+        None => {}
+    }
+
+    let profile = profiles::Profile::new(&args).expect("valid profile required");
+    let engine = stt_engine::new_engine(&args, &profile);
+
+    let vox_audio = Arc::new(audio::VoxAudio::new(&args, engine));
     let input_config = vox_audio.input_stream_config();
     let key_delay = Duration::from_millis(args.key_delay);
-    // toml profile config
-    let config = Arc::new(Mutex::new(profiles::Config::new(&args)));
+    // toml profile config, built after loading the model so that the prompt fits it
+    let config = Arc::new(Mutex::new(
+        profiles::Config::from_profile(profile).expect("valid profile required"),
+    ));
     let record_keybind = config.lock().unwrap().profile.record_keybind;
 
     info!(
@@ -38,7 +68,29 @@ pub fn main() {
 
     // -------------------------------------------------------------------------
 
-    let stream: Arc<Mutex<Option<VoxStream>>> = Arc::new(Mutex::new(None));
+    let reload_keybind = config.lock().unwrap().profile.reload_keybind;
+    if let Some(reload_keybind) = reload_keybind {
+        let reload_config = config.clone();
+        let reload_args = args.clone();
+        reload_keybind.bind(move || reload_profile(&reload_config, &reload_args));
+    }
+
+    if args.open_mic {
+        if reload_keybind.is_some() {
+            thread::spawn(|| inputbot::handle_input_events(false));
+        }
+        return run_open_mic(vox_audio, config, key_delay);
+    }
+
+    // with pre-roll the stream stays open and is reused for every utterance
+    let keep_stream_open = args.pre_roll_ms > 0;
+    let initial_stream = if keep_stream_open {
+        info!("[RECORDING] keeping {} ms of pre-roll", args.pre_roll_ms);
+        Some(vox_audio.new_pre_roll_stream())
+    } else {
+        None
+    };
+    let stream: Arc<Mutex<Option<VoxStream>>> = Arc::new(Mutex::new(initial_stream));
     let vox1 = vox_audio.clone();
     record_keybind.bind(move || {
         let this = record_keybind;
@@ -51,39 +103,63 @@ pub fn main() {
             return;
         }
 
-        if local_stream.is_none() {
-            info!("[RECORDING] starting new audio input stream");
-            local_stream.replace(vox1.new_stream(true));
+        match local_stream.as_ref() {
+            Some(s) => s.start_utterance(),
+            None => {
+                info!("[RECORDING] starting new audio input stream");
+                local_stream.replace(vox1.new_stream(true));
+            }
         }
+        let mut partials = {
+            let local_config = config.lock().unwrap();
+            let profile = &local_config.profile;
+            profile
+                .streaming
+                .enabled
+                .then(|| streaming::PartialTranscriber::new(profile))
+        };
         while this.is_pressed() {
             sleep(Duration::from_millis(50));
+            if let Some(s) = local_stream.as_ref() {
+                s.drain();
+                if let Some(p) = partials.as_mut() {
+                    handle_partial(&config, p, s, key_delay);
+                }
+            }
         }
-
-        // this could be changed to bind_release which is only on windows
-        let local_stream = local_stream.take();
-        if local_stream.is_none() {
-            warn!("[RECORDING] could not get local stream");
-            return;
+        if let Some(p) = partials {
+            p.finish();
         }
 
         let local_config = config.lock().unwrap();
-        let stream_result = local_stream.unwrap().finish_stream(
-            &local_config.profile.whisper.initial_prompt,
-            input_config.channels(),
-        );
-        info!("[RECORDING] stream result: {}", stream_result);
-
-        let rgx = PROMPT_REGEX.get().expect("regex required");
-        let processed_result = rgx.replace_all(&stream_result, "").to_lowercase();
-        let command = local_config.get_command(&processed_result);
-        match command {
-            None => info!("[ACTION] no command found with {}", processed_result),
-            Some(c) => {
-                info!("[ACTION] executing command '{}'", c.name);
-                c.execute(key_delay);
-                info!("[ACTION] command finished")
+        let profile = &local_config.profile;
+        let stream_result = if keep_stream_open {
+            match local_stream.as_ref() {
+                Some(s) => s.finish_utterance(profile),
+                None => {
+                    warn!("[RECORDING] could not get local stream");
+                    return;
+                }
             }
-        }
+        } else {
+            // this could be changed to bind_release which is only on windows
+            match local_stream.take() {
+                Some(s) => s.finish_stream(profile),
+                None => {
+                    warn!("[RECORDING] could not get local stream");
+                    return;
+                }
+            }
+        };
+        let stream_result = match stream_result {
+            None => {
+                info!("[RECORDING] no speech detected");
+                return;
+            }
+            Some(x) => x,
+        };
+        info!("[RECORDING] stream result: {}", stream_result.text());
+        execute_transcript(&local_config, &stream_result, |x| x.execute(key_delay));
 
         // let mut enigo = Enigo::new(&Settings::default()).unwrap();
         // match enigo.key(Key::Unicode('s'), Direction::Click) {
@@ -96,3 +172,283 @@ pub fn main() {
 
     inputbot::handle_input_events(false);
 }
+
+/// Re-reads the profile file and switches to the model it asks for,
+/// the current profile is kept when the file is not a valid profile
+fn reload_profile(config: &Mutex<profiles::Config>, args: &CommandArguments) {
+    info!("[PROFILE] reloading {}", args.profile_path);
+    let mut new_config = match profiles::Config::new(args) {
+        Err(e) => {
+            warn!("[PROFILE] keeping the current profile, {:#}", e);
+            return;
+        }
+        Ok(x) => x,
+    };
+    new_config.apply_model(args);
+
+    let mut local_config = config.lock().unwrap();
+    if local_config.profile.record_keybind != new_config.profile.record_keybind {
+        warn!("[PROFILE] record_keybind changes need a restart");
+    }
+    *local_config = new_config;
+    info!(
+        "[PROFILE] reloaded with {} commands",
+        local_config.profile.commands.len()
+    );
+}
+
+/// Executes the command of a finished partial transcript when it may fire early,
+/// the audio it was heard in is dropped so that releasing the key does not fire it again
+fn handle_partial(
+    config: &Mutex<profiles::Config>,
+    partials: &mut streaming::PartialTranscriber,
+    stream: &VoxStream,
+    key_delay: Duration,
+) {
+    let partial = match partials.poll(stream) {
+        None => return,
+        Some(x) => x,
+    };
+    let local_config = config.lock().unwrap();
+    if let Some(command) = partials.early_command(&local_config, &partial) {
+        info!("[ACTION] executing command '{}' early", command.name);
+        stream.discard_audio(partial.end);
+        command.execute(key_delay);
+        info!("[ACTION] command finished");
+    }
+}
+
+/// Finds the command matching the transcript and executes it with `execute`
+fn execute_transcript(
+    config: &profiles::Config,
+    transcript: &Transcript,
+    execute: impl FnMut(&profiles::Command),
+) {
+    if is_vetoed(config, transcript) {
+        return;
+    }
+    execute_text(config, &transcript.text(), execute);
+}
+
+/// transcripts that are not confident enough never trigger a command
+fn is_vetoed(config: &profiles::Config, transcript: &Transcript) -> bool {
+    match transcript.veto(&config.profile.whisper) {
+        None => return false,
+        Some(reason) => {
+            info!("[ACTION] ignoring '{}', {}", transcript.text(), reason);
+            return true;
+        }
+    }
+}
+
+/// Same as [execute_transcript] without the veto, `text` may already be normalized\
+/// every command named in `text` is executed in order, `command_gap_ms` apart\
+/// the gap is only waited between executed commands, parts that match nothing add no delay
+fn execute_text(
+    config: &profiles::Config,
+    text: &str,
+    mut execute: impl FnMut(&profiles::Command),
+) {
+    let matches = config.match_commands(text);
+    if matches.len() > 1 {
+        info!("[ACTION] '{}' names {} commands", text, matches.len());
+    }
+
+    let gap = Duration::from_millis(config.profile.matching.command_gap_ms);
+    let mut executed_any = false;
+    for (segment, command) in matches {
+        match command {
+            None => info!("[ACTION] no command found with {}", segment),
+            Some(c) => {
+                if executed_any {
+                    sleep(gap);
+                }
+                info!("[ACTION] executing command '{}' for '{}'", c.name, segment);
+                execute(c);
+                info!("[ACTION] command finished");
+                executed_any = true;
+            }
+        }
+    }
+}
+
+/// Hands-free mode -- audio is captured all the time and every utterance found by
+/// voice activity detection goes through the same pipeline as the record keybind.
+/// The vad and wake word settings follow profile reloads
+fn run_open_mic(
+    vox_audio: Arc<audio::VoxAudio>,
+    config: Arc<Mutex<profiles::Config>>,
+    key_delay: Duration,
+) {
+    let mut wake_word = config.lock().unwrap().profile.wake_word.clone();
+    let mut wake_word_gate = wake_word.as_ref().map(wake_word::WakeWordGate::new);
+    let (sender, receiver) = mpsc::channel::<Vec<f32>>();
+
+    // capturing has its own thread so that no audio is lost while whisper is busy
+    let capture_audio = vox_audio.clone();
+    let capture_config = config.clone();
+    thread::spawn(move || {
+        let stream = capture_audio.new_stream(true);
+        let mut vad_settings = capture_config.lock().unwrap().profile.vad.clone();
+        let mut vad = vad::VoiceActivityDetector::new(&vad_settings, stream.sample_rate);
+        info!("[OPEN MIC] listening");
+
+        loop {
+            sleep(Duration::from_millis(50));
+            // the config stays locked while whisper runs, waiting for it would lose audio
+            if let Ok(local_config) = capture_config.try_lock() {
+                if local_config.profile.vad != vad_settings {
+                    info!("[OPEN MIC] vad settings changed, restarting detection");
+                    vad_settings = local_config.profile.vad.clone();
+                    vad = vad::VoiceActivityDetector::new(&vad_settings, stream.sample_rate);
+                }
+            }
+            for utterance in vad.process(&stream.take_mono_audio()) {
+                debug!(
+                    "[OPEN MIC] detected utterance of {} samples",
+                    utterance.len()
+                );
+                if sender.send(utterance).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    for utterance in receiver {
+        let local_config = config.lock().unwrap();
+        let stream_result = match vox_audio.transcribe_mono(utterance, &local_config.profile) {
+            None => continue,
+            Some(x) => x,
+        };
+        info!("[OPEN MIC] stream result: {}", stream_result.text());
+        if is_vetoed(&local_config, &stream_result) {
+            continue;
+        }
+
+        if local_config.profile.wake_word != wake_word {
+            wake_word = local_config.profile.wake_word.clone();
+            wake_word_gate = wake_word.as_ref().map(wake_word::WakeWordGate::new);
+        }
+
+        let text = match wake_word_gate.as_mut() {
+            None => stream_result.text(),
+            Some(gate) => {
+                match gate.check(&stream_result.text(), |x| local_config.starts_command(x)) {
+                    None => continue,
+                    Some(x) => x,
+                }
+            }
+        };
+        execute_text(&local_config, &text, |x| x.execute(key_delay));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiles::{Config, Profile};
+    use crate::stt_engine::Segment;
+    use std::time::Instant;
+
+    const PROFILE: &str = r#"
+record_keybind = "numpad1"
+
+[whisper]
+initial_prompt = ""
+min_confidence = 0.6
+max_no_speech = 0.5
+
+[matching]
+command_gap_ms = 200
+
+[[commands]]
+name = "resupply"
+action = "swsa"
+
+[[commands]]
+name = "reinforce"
+action = "wsdaw"
+"#;
+
+    fn config() -> Config {
+        let profile = Profile::parse(PROFILE, "test profile").expect("valid profile required");
+        return Config::from_profile(profile).expect("valid profile required");
+    }
+
+    fn transcript(text: &str, confidence: f32) -> Transcript {
+        return Transcript {
+            segments: vec![Segment {
+                text: text.to_string(),
+                confidence,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+    }
+
+    /// names of the executed commands with the time they were executed at
+    fn execute_with(
+        run: impl FnOnce(&mut dyn FnMut(&profiles::Command)),
+    ) -> Vec<(String, Instant)> {
+        let mut executed = Vec::new();
+        run(&mut |x| executed.push((x.name.clone(), Instant::now())));
+        return executed;
+    }
+
+    fn names(executed: &[(String, Instant)]) -> Vec<&str> {
+        return executed.iter().map(|(name, _)| name.as_str()).collect();
+    }
+
+    #[test]
+    fn confident_transcripts_are_executed() {
+        let config = config();
+        let executed = execute_with(|execute| {
+            execute_transcript(&config, &transcript("Resupply.", 0.9), execute)
+        });
+        assert_eq!(names(&executed), ["resupply"]);
+    }
+
+    #[test]
+    fn vetoed_transcripts_execute_nothing() {
+        let config = config();
+        let low_confidence = transcript("Resupply.", 0.3);
+        assert!(is_vetoed(&config, &low_confidence));
+        let executed =
+            execute_with(|execute| execute_transcript(&config, &low_confidence, execute));
+        assert!(executed.is_empty());
+
+        let mut no_speech = transcript("Resupply.", 0.9);
+        no_speech.segments[0].no_speech_probability = Some(0.8);
+        assert!(is_vetoed(&config, &no_speech));
+        let executed = execute_with(|execute| execute_transcript(&config, &no_speech, execute));
+        assert!(executed.is_empty());
+
+        no_speech.segments[0].no_speech_probability = Some(0.2);
+        assert!(!is_vetoed(&config, &no_speech));
+    }
+
+    #[test]
+    fn gap_is_only_waited_between_executed_commands() {
+        let config = config();
+        let gap = Duration::from_millis(config.profile.matching.command_gap_ms);
+
+        let start = Instant::now();
+        let executed = execute_with(|execute| {
+            execute_text(&config, "resupply and mortar and reinforce", execute)
+        });
+        assert_eq!(names(&executed), ["resupply", "reinforce"]);
+        // the unmatched "mortar" in between does not add another gap
+        let between = executed[1].1.duration_since(executed[0].1);
+        assert!(between >= gap, "{:?}", between);
+        assert!(between < gap * 2, "{:?}", between);
+        assert!(executed[0].1.duration_since(start) < gap);
+
+        // nothing to wait for after a single command
+        let start = Instant::now();
+        let executed =
+            execute_with(|execute| execute_text(&config, "mortar and resupply", execute));
+        assert_eq!(names(&executed), ["resupply"]);
+        assert!(start.elapsed() < gap);
+    }
+}
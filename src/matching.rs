@@ -0,0 +1,285 @@
+/// Fuzzy matching of transcripts against command names
+///
+/// Every command name is scored by normalized edit distance, once over the whole name and once
+/// word by word (so "laser orbital" still finds "orbital laser"), the better score counts.
+use crate::speech_to_text::{normalize_prompt, normalize_words};
+use log::info;
+use serde::Deserialize;
+use strsim::normalized_levenshtein;
+
+/// how many of the best scoring names are logged
+const LOGGED_CANDIDATES: usize = 3;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MatchingSettings {
+    /// lowest score (0.0 to 1.0) a name needs to match when it is not exact, 1.0 disables fuzzy matching
+    pub fuzzy_threshold: f64,
+    /// the best command has to score at least this much more than the next best one
+    pub min_margin: f64,
+    /// words ("and") or punctuation (",") that separate several commands in one utterance
+    pub conjunctions: Vec<String>,
+    /// pause (in milliseconds) between commands of the same utterance
+    pub command_gap_ms: u64,
+}
+
+impl Default for MatchingSettings {
+    fn default() -> Self {
+        Self {
+            fuzzy_threshold: 0.8,
+            min_margin: 0.05,
+            conjunctions: vec!["and".to_string(), "then".to_string(), ",".to_string()],
+            command_gap_ms: 100,
+        }
+    }
+}
+
+impl MatchingSettings {
+    /// Checks that the settings make sense, returns every problem found
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !(0.0..=1.0).contains(&self.fuzzy_threshold) {
+            problems.push("matching.fuzzy_threshold must be between 0.0 and 1.0".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.min_margin) {
+            problems.push("matching.min_margin must be between 0.0 and 1.0".to_string());
+        }
+        if self.conjunctions.iter().any(|x| x.trim().is_empty()) {
+            problems.push("matching.conjunctions can not contain empty strings".to_string());
+        }
+        return problems;
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+#[derive(Debug)]
+struct Candidate {
+    name: String,
+    /// normalized the same way as transcripts
+    processed: String,
+    words: Vec<String>,
+    /// index into profile.commands
+    index: usize,
+}
+
+#[derive(Debug)]
+pub struct FuzzyMatcher {
+    candidates: Vec<Candidate>,
+    settings: MatchingSettings,
+}
+
+impl FuzzyMatcher {
+    /// `names` are command names with the index of their command
+    pub fn new<'a>(
+        names: impl Iterator<Item = (&'a String, usize)>,
+        settings: &MatchingSettings,
+    ) -> Self {
+        let candidates = names
+            .map(|(name, index)| Candidate {
+                name: name.clone(),
+                processed: normalize_prompt(name),
+                words: normalize_words(name),
+                index,
+            })
+            .collect();
+        return Self {
+            candidates,
+            settings: settings.clone(),
+        };
+    }
+
+    /// Index of the command whose name is closest to `text`\
+    /// `None` when no name is close enough or the two best commands are too close to each other
+    pub fn find(&self, text: &str) -> Option<usize> {
+        if self.settings.fuzzy_threshold >= 1.0 {
+            return None;
+        }
+
+        let processed = normalize_prompt(text);
+        let words = normalize_words(text);
+        let mut scored: Vec<(f64, &Candidate)> = self
+            .candidates
+            .iter()
+            .map(|x| (score(&processed, &words, x), x))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        // names of the same command (translations) do not compete with each other
+        let mut best_per_command: Vec<(f64, &Candidate)> = Vec::new();
+        for (score, candidate) in scored {
+            if !best_per_command
+                .iter()
+                .any(|(_, x)| x.index == candidate.index)
+            {
+                best_per_command.push((score, candidate));
+            }
+        }
+
+        let candidates: Vec<String> = best_per_command
+            .iter()
+            .take(LOGGED_CANDIDATES)
+            .map(|(score, x)| format!("'{}' {:.2}", x.name, score))
+            .collect();
+        info!(
+            "[MATCH] candidates for '{}': {}",
+            text,
+            candidates.join(", ")
+        );
+
+        let (best_score, best) = *best_per_command.first()?;
+        if best_score < self.settings.fuzzy_threshold {
+            info!(
+                "[MATCH] best score {:.2} is below {:.2}",
+                best_score, self.settings.fuzzy_threshold
+            );
+            return None;
+        }
+        if let Some((runner_up_score, runner_up)) = best_per_command.get(1) {
+            if best_score - runner_up_score < self.settings.min_margin {
+                info!(
+                    "[MATCH] '{}' and '{}' are too close to tell apart",
+                    best.name, runner_up.name
+                );
+                return None;
+            }
+        }
+        return Some(best.index);
+    }
+}
+
+/// Splits a transcript that names several commands, e.g. "resupply and reinforce"\
+/// conjunctions that are words are only split on as whole words, the others (",") anywhere
+pub fn split_utterance(text: &str, conjunctions: &[String]) -> Vec<String> {
+    let (words, marks): (Vec<&String>, Vec<&String>) = conjunctions
+        .iter()
+        .partition(|x| !normalize_prompt(x).is_empty());
+    let words: Vec<String> = words.into_iter().map(|x| normalize_prompt(x)).collect();
+
+    let mut pieces = vec![text.to_string()];
+    for mark in marks {
+        let mark = mark.trim();
+        pieces = pieces
+            .iter()
+            .flat_map(|x| x.split(mark))
+            .map(str::to_string)
+            .collect();
+    }
+
+    let mut segments = Vec::new();
+    for piece in &pieces {
+        let mut current: Vec<&str> = Vec::new();
+        for word in piece.split_whitespace() {
+            if words.contains(&normalize_prompt(word)) {
+                segments.push(current.join(" "));
+                current.clear();
+            } else {
+                current.push(word);
+            }
+        }
+        segments.push(current.join(" "));
+    }
+
+    segments.retain(|x| !normalize_prompt(x).is_empty());
+    return segments;
+}
+
+/// similarity of a transcript to a command name, 0.0 to 1.0
+fn score(processed: &str, words: &[String], candidate: &Candidate) -> f64 {
+    let characters = normalized_levenshtein(processed, &candidate.processed);
+    return characters.max(word_score(words, &candidate.words));
+}
+
+/// Every word of the name is paired with the most similar transcript word, regardless of order.
+/// Extra or missing words count as a score of 0
+fn word_score(words: &[String], name_words: &[String]) -> f64 {
+    if words.is_empty() || name_words.is_empty() {
+        return 0.0;
+    }
+
+    let total: f64 = name_words
+        .iter()
+        .map(|name_word| {
+            words
+                .iter()
+                .map(|x| normalized_levenshtein(x, name_word))
+                .fold(0.0, f64::max)
+        })
+        .sum();
+    return total / words.len().max(name_words.len()) as f64;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fuzzy(commands: &[&[&str]], settings: &MatchingSettings) -> FuzzyMatcher {
+        let names: Vec<(String, usize)> = commands
+            .iter()
+            .enumerate()
+            .flat_map(|(index, names)| names.iter().map(move |x| (x.to_string(), index)))
+            .collect();
+        return FuzzyMatcher::new(names.iter().map(|(name, index)| (name, *index)), settings);
+    }
+
+    const COMMANDS: &[&[&str]] = &[
+        &["orbital laser"],
+        &["orbital railcannon strike"],
+        &["resupply"],
+        &["reinforce", "reinforcements"],
+    ];
+
+    #[test]
+    fn misheard_names_match() {
+        let matcher = fuzzy(COMMANDS, &MatchingSettings::default());
+        assert_eq!(matcher.find("orbital lazer"), Some(0));
+        assert_eq!(matcher.find("Orbital lazer!"), Some(0));
+        assert_eq!(matcher.find("resuply"), Some(2));
+    }
+
+    #[test]
+    fn word_order_does_not_matter() {
+        let matcher = fuzzy(COMMANDS, &MatchingSettings::default());
+        assert_eq!(matcher.find("laser orbital"), Some(0));
+    }
+
+    #[test]
+    fn below_threshold_is_rejected() {
+        let matcher = fuzzy(COMMANDS, &MatchingSettings::default());
+        assert_eq!(matcher.find("mortar sentry"), None);
+        assert_eq!(matcher.find(""), None);
+
+        let disabled = MatchingSettings {
+            fuzzy_threshold: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(fuzzy(COMMANDS, &disabled).find("orbital lazer"), None);
+    }
+
+    #[test]
+    fn close_runner_up_is_rejected() {
+        let commands: &[&[&str]] = &[&["airstrike"], &["airstrikes"]];
+        // one letter away from both names
+        let matcher = fuzzy(commands, &MatchingSettings::default());
+        assert_eq!(matcher.find("airstrikee"), None);
+        // a clear winner still matches
+        assert_eq!(matcher.find("airstrik"), Some(0));
+
+        let no_margin = MatchingSettings {
+            min_margin: 0.0,
+            ..Default::default()
+        };
+        assert!(fuzzy(commands, &no_margin).find("airstrikee").is_some());
+    }
+
+    #[test]
+    fn names_of_one_command_do_not_compete() {
+        // both names score the same, as two commands they would be too close to tell apart
+        let commands: &[&[&str]] = &[&["airstrike", "air strike"], &["resupply"]];
+        let matcher = fuzzy(commands, &MatchingSettings::default());
+        assert_eq!(matcher.find("airstrik"), Some(0));
+
+        let commands: &[&[&str]] = &[&["airstrike"], &["air strike"], &["resupply"]];
+        let matcher = fuzzy(commands, &MatchingSettings::default());
+        assert_eq!(matcher.find("airstrik"), None);
+    }
+}
@@ -0,0 +1,203 @@
+/// Reading ggml whisper model files without handing them to whisper.cpp
+///
+/// whisper.cpp only says that loading failed, reading the header ourselves
+/// gives a useful error for missing, truncated or non-whisper files.
+use anyhow::{anyhow, bail, Context};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    f32::consts::PI,
+    fs::{self, File},
+    io::{BufReader, Read},
+    path::Path,
+};
+
+/// "ggml" in little endian
+const GGML_MAGIC: u32 = 0x67676d6c;
+/// whisper.cpp stores the quantization version multiplied by this in `ftype`
+const GGML_QNT_VERSION_FACTOR: i32 = 1000;
+/// english-only models have one token less than the multilingual ones
+const MULTILINGUAL_VOCAB: i32 = 51865;
+
+#[derive(Debug)]
+pub struct ModelInfo {
+    pub file_size: u64,
+    pub n_vocab: i32,
+    pub n_audio_ctx: i32,
+    pub n_audio_state: i32,
+    pub n_audio_head: i32,
+    pub n_audio_layer: i32,
+    pub n_text_ctx: i32,
+    pub n_text_state: i32,
+    pub n_text_head: i32,
+    pub n_text_layer: i32,
+    pub n_mels: i32,
+    pub ftype: i32,
+}
+
+impl ModelInfo {
+    pub fn read(path: &str) -> Result<Self, anyhow::Error> {
+        let file = File::open(path).with_context(|| format!("could not open model '{}'", path))?;
+        let file_size = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+
+        let magic = read_u32(&mut reader).context("file is too small to be a model")?;
+        if magic != GGML_MAGIC {
+            bail!(
+                "'{}' is not a ggml model (magic {:#010x}), see https://github.com/ggerganov/whisper.cpp/blob/master/models/README.md",
+                path,
+                magic
+            );
+        }
+
+        let mut hparams = [0_i32; 11];
+        for value in hparams.iter_mut() {
+            *value = read_u32(&mut reader).context("model header is truncated")? as i32;
+        }
+        let [n_vocab, n_audio_ctx, n_audio_state, n_audio_head, n_audio_layer, n_text_ctx, n_text_state, n_text_head, n_text_layer, n_mels, ftype] =
+            hparams;
+
+        return Ok(Self {
+            file_size,
+            n_vocab,
+            n_audio_ctx,
+            n_audio_state,
+            n_audio_head,
+            n_audio_layer,
+            n_text_ctx,
+            n_text_state,
+            n_text_head,
+            n_text_layer,
+            n_mels,
+            ftype,
+        });
+    }
+
+    /// tiny, base, small, medium or large, going by the amount of encoder layers
+    pub fn model_type(&self) -> &'static str {
+        match self.n_audio_layer {
+            4 => "tiny",
+            6 => "base",
+            12 => "small",
+            24 => "medium",
+            32 => "large",
+            _ => "unknown",
+        }
+    }
+
+    pub fn is_multilingual(&self) -> bool {
+        return self.n_vocab >= MULTILINGUAL_VOCAB;
+    }
+
+    /// weight type, for example "f16" or "q5_0"
+    pub fn quantization(&self) -> &'static str {
+        match self.ftype % GGML_QNT_VERSION_FACTOR {
+            0 => "f32",
+            1 => "f16",
+            2 => "q4_0",
+            3 => "q4_1",
+            4 => "q4_1 (some f16)",
+            7 => "q8_0",
+            8 => "q5_0",
+            9 => "q5_1",
+            10 => "q2_k",
+            11 => "q3_k",
+            12 => "q4_k",
+            13 => "q5_k",
+            14 => "q6_k",
+            _ => "unknown",
+        }
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, std::io::Error> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    return Ok(u32::from_le_bytes(bytes));
+}
+
+// -----------------------------------------------------------------------------
+
+/// lowercase hex SHA-256 of the file at `path`
+pub fn sha256_file(path: &str) -> Result<String, anyhow::Error> {
+    let mut reader =
+        BufReader::new(File::open(path).with_context(|| format!("could not open '{}'", path))?);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    let hash = hasher.finalize();
+    return Ok(hash.iter().map(|x| format!("{:02x}", x)).collect());
+}
+
+/// Reads a manifest in the format `sha256sum` writes (`<hash>  <file name>`),
+/// returns hashes by file name
+pub fn read_manifest(path: &str) -> Result<HashMap<String, String>, anyhow::Error> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("could not read manifest '{}'", path))?;
+    let mut hashes = HashMap::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (hash, name) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("line {} of '{}' has no file name", number + 1, path))?;
+        // `sha256sum --binary` marks file names with `*`
+        let name = name.trim().trim_start_matches('*');
+        hashes.insert(file_name(name), hash.to_lowercase());
+    }
+    return Ok(hashes);
+}
+
+/// the last component of `path`, manifests are matched by file name only
+pub fn file_name(path: &str) -> String {
+    return Path::new(path)
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or(path.to_string());
+}
+
+// -----------------------------------------------------------------------------
+
+/// Voice-like test signal for benchmarking, a buzz with a few formants that is
+/// amplitude modulated at a syllable rate, with a bit of noise on top
+pub fn synthetic_speech(seconds: f32, sample_rate: u32) -> Vec<f32> {
+    const FUNDAMENTAL: f32 = 120.0;
+    const FORMANTS: [(f32, f32); 3] = [(700.0, 1.0), (1200.0, 0.5), (2600.0, 0.25)];
+    const SYLLABLE_RATE: f32 = 4.0;
+
+    let length = (seconds * sample_rate as f32) as usize;
+    // deterministic noise so that every run gets the same audio
+    let mut noise_state: u32 = 0x1234_5678;
+    let mut audio = Vec::with_capacity(length);
+    for n in 0..length {
+        let t = n as f32 / sample_rate as f32;
+        let mut sample = 0.0;
+        let mut harmonic = FUNDAMENTAL;
+        while harmonic < sample_rate as f32 / 2.0 {
+            let gain: f32 = FORMANTS
+                .iter()
+                .map(|(center, gain)| gain / (1.0 + ((harmonic - center) / 150.0).powi(2)))
+                .sum();
+            sample += gain * (2.0 * PI * harmonic * t).sin();
+            harmonic += FUNDAMENTAL;
+        }
+
+        noise_state = noise_state
+            .wrapping_mul(1_664_525)
+            .wrapping_add(1_013_904_223);
+        let noise = (noise_state >> 8) as f32 / (1 << 24) as f32 - 0.5;
+        let envelope = 0.5 - 0.5 * (2.0 * PI * SYLLABLE_RATE * t).cos();
+        audio.push(0.1 * envelope * sample + 0.005 * noise);
+    }
+    return audio;
+}
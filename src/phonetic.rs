@@ -0,0 +1,165 @@
+/// Phonetic matching of transcripts against command names
+///
+/// Whisper often hears the right sounds but picks another spelling ("quazar", "stalwort").
+/// Names are indexed by their Double Metaphone keys, with the words joined so that
+/// "rail gun" and "railgun" get the same key.
+use crate::speech_to_text::normalize_words;
+use log::info;
+use rphonetic::DoubleMetaphone;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhoneticKeys {
+    pub primary: String,
+    /// differs from `primary` for words with another common pronunciation
+    pub alternate: String,
+}
+
+impl PhoneticKeys {
+    /// `None` when nothing in `text` can be encoded, e.g. non-latin scripts
+    pub fn new(text: &str) -> Option<Self> {
+        // no maximum length, `DoubleMetaphone::default()` would cut every key to 4 letters
+        // and "reinforce" and "reinforcements" would sound the same
+        let encoder = DoubleMetaphone::new(None);
+        let mut keys = Self {
+            primary: String::new(),
+            alternate: String::new(),
+        };
+        for word in normalize_words(text) {
+            let result = encoder.double_metaphone(&word);
+            keys.primary.push_str(&result.primary());
+            keys.alternate.push_str(&result.alternate());
+        }
+
+        if keys.primary.is_empty() {
+            return None;
+        }
+        return Some(keys);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PhoneticIndex {
+    /// commands by primary and alternate key
+    commands: BTreeMap<String, BTreeSet<usize>>,
+}
+
+impl PhoneticIndex {
+    /// `names` are command names with the index of their command
+    pub fn new<'a>(names: impl Iterator<Item = (&'a String, usize)>) -> Self {
+        let mut index = Self::default();
+        for (name, command_index) in names {
+            let keys = match PhoneticKeys::new(name) {
+                None => continue,
+                Some(x) => x,
+            };
+            for key in [keys.primary, keys.alternate] {
+                index.commands.entry(key).or_default().insert(command_index);
+            }
+        }
+        return index;
+    }
+
+    /// Index of the only command that sounds like `text`, `None` when none or several do
+    pub fn find(&self, text: &str) -> Option<usize> {
+        let keys = PhoneticKeys::new(text)?;
+        for key in [&keys.primary, &keys.alternate] {
+            let commands = match self.commands.get(key) {
+                None => continue,
+                Some(x) => x,
+            };
+            if commands.len() > 1 {
+                info!(
+                    "[PHONETIC] '{}' ({}) sounds like {} commands",
+                    text,
+                    key,
+                    commands.len()
+                );
+                return None;
+            }
+            info!("[PHONETIC] '{}' matched by sound ({})", text, key);
+            return commands.first().copied();
+        }
+        return None;
+    }
+
+    /// keys that more than one command has, those commands can not be told apart by sound
+    pub fn collisions(&self) -> Vec<(&str, &BTreeSet<usize>)> {
+        return self
+            .commands
+            .iter()
+            .filter(|(_, commands)| commands.len() > 1)
+            .map(|(key, commands)| (key.as_str(), commands))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phonetic_index(commands: &[&[&str]]) -> PhoneticIndex {
+        let names: Vec<(String, usize)> = commands
+            .iter()
+            .enumerate()
+            .flat_map(|(index, names)| names.iter().map(move |x| (x.to_string(), index)))
+            .collect();
+        return PhoneticIndex::new(names.iter().map(|(name, index)| (name, *index)));
+    }
+
+    fn primary(text: &str) -> String {
+        return PhoneticKeys::new(text).expect("encodable text").primary;
+    }
+
+    #[test]
+    fn words_are_joined() {
+        assert_eq!(primary("rail gun"), primary("railgun"));
+        assert_eq!(primary("Rail-gun!"), primary("railgun"));
+    }
+
+    #[test]
+    fn keys_are_not_cut_off() {
+        assert_ne!(primary("reinforce"), primary("reinforcements"));
+        assert!(primary("orbital railcannon strike").len() > 4);
+    }
+
+    #[test]
+    fn nothing_to_encode() {
+        assert_eq!(PhoneticKeys::new(""), None);
+        assert_eq!(PhoneticKeys::new("?!"), None);
+    }
+
+    #[test]
+    fn misspellings_sound_like_names() {
+        let index = phonetic_index(&[
+            &["stalwart"],
+            &["quasar cannon"],
+            &["railgun"],
+            &["resupply"],
+        ]);
+        assert_eq!(index.find("stalwort"), Some(0));
+        assert_eq!(index.find("quazar cannon"), Some(1));
+        assert_eq!(index.find("rail gun"), Some(2));
+        assert_eq!(index.find("orbital laser"), None);
+        assert!(index.collisions().is_empty());
+    }
+
+    #[test]
+    fn shared_keys_are_ambiguous() {
+        // "quasar" and "quazar" are spelled differently but sound the same
+        let index = phonetic_index(&[&["quasar"], &["quazar"], &["resupply"]]);
+        assert_eq!(index.find("quasar"), None);
+
+        let collisions = index.collisions();
+        assert_eq!(collisions.len(), 1, "{:?}", collisions);
+        let (key, commands) = collisions[0];
+        assert_eq!(key, primary("quasar"));
+        assert_eq!(commands.iter().copied().collect::<Vec<_>>(), [0, 1]);
+
+        // names of one command do not collide
+        let commands: &[&[&str]] = &[&["quasar", "quazar"], &["resupply"]];
+        let index = phonetic_index(commands);
+        assert!(index.collisions().is_empty());
+        assert_eq!(index.find("quasar"), Some(0));
+    }
+}
@@ -1,10 +1,27 @@
+use anyhow::{bail, Context};
 use inputbot::KeybdKey;
 use regex::Regex;
 use serde::Deserialize;
-use std::{collections::HashMap, fs, thread::sleep, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+    thread::sleep,
+    time::Duration,
+};
 
 use crate::{
-    inputbot_patch::KeySequence, settings::CommandArguments, speech_to_text::PROMPT_REGEX,
+    inputbot_patch::KeySequence,
+    matching::{split_utterance, FuzzyMatcher, MatchingSettings},
+    phonetic::PhoneticIndex,
+    settings::CommandArguments,
+    speech_to_text::{self, normalize_prompt, normalize_words, AUTO_LANGUAGE},
+    streaming::StreamingSettings,
+    trim::TrimSettings,
+    vad::VadSettings,
+    validator::validate_commands,
+    vocabulary::{build_prompt, ClosedVocabulary},
+    wake_word::WakeWord,
 };
 
 // -----------------------------------------------------------------------------
@@ -16,9 +33,23 @@ pub struct Command {
     pub name: String,
     pub action: String,
     pub modifiers: Option<Vec<KeybdKey>>,
+    /// other phrasings that trigger the same action, e.g. `["air strike", "eagle airstrike"]`
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// the name in other languages by language code, for example `{ de = "Orbitallaser" }`\
+    /// each one triggers the same action as `name`
+    #[serde(default)]
+    pub translations: BTreeMap<String, String>,
 }
 
 impl Command {
+    /// `name` followed by the aliases and translations
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        return std::iter::once(&self.name)
+            .chain(self.aliases.iter())
+            .chain(self.translations.values());
+    }
+
     pub fn execute(&self, delay: Duration) {
         match &self.modifiers {
             None => None,
@@ -40,34 +71,218 @@ impl Command {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DecodingStrategy {
+    /// uses `best_of`
+    Greedy,
+    /// uses `beam_size` and `patience`
+    #[default]
+    BeamSearch,
+}
+
+/// Decoding parameters, the `Option`s fall back to whisper.cpp defaults\
+/// see https://github.com/ggerganov/whisper.cpp/blob/master/whisper.h for what they do
+#[derive(Deserialize, Debug, Clone)]
 pub struct Whisper {
+    /// put before the glossary that is generated from the command names
+    #[serde(default)]
     pub initial_prompt: String,
+    /// used as is instead of the generated prompt
+    pub prompt_override: Option<String>,
+    #[serde(default)]
+    pub strategy: DecodingStrategy,
+    #[serde(default = "Whisper::default_best_of")]
+    pub best_of: i32,
+    #[serde(default = "Whisper::default_beam_size")]
+    pub beam_size: i32,
+    /// not implemented in whisper.cpp yet, -1.0 disables it
+    #[serde(default = "Whisper::default_patience")]
+    pub patience: f32,
+    pub threads: Option<i32>,
+    pub temperature: Option<f32>,
+    pub temperature_inc: Option<f32>,
+    /// language code like "en" or "de", "auto" lets whisper detect it (multilingual models only)
+    #[serde(default = "Whisper::default_language")]
+    pub language: String,
+    pub suppress_blank: Option<bool>,
+    pub no_speech_thold: Option<f32>,
+    /// maximum tokens per segment, 0 for no limit
+    pub max_tokens: Option<i32>,
+    /// transcripts less confident than this (0.0 to 1.0) never trigger a command
+    #[serde(default)]
+    pub min_confidence: f32,
+    /// transcripts more likely than this (0.0 to 1.0) to not be speech never trigger a command
+    #[serde(default = "Whisper::default_max_no_speech")]
+    pub max_no_speech: f32,
+    /// steer decoding towards the command names
+    #[serde(default)]
+    pub closed_vocabulary: ClosedVocabulary,
+    /// added to the logits of tokens that continue a command name with `closed_vocabulary = "bias"`
+    #[serde(default = "Whisper::default_vocabulary_bias")]
+    pub vocabulary_bias: f32,
+    /// command names, aliases and translations, filled in by [Config::new]
+    #[serde(skip)]
+    pub vocabulary: Vec<String>,
+    /// what is actually given to whisper, built by [Config::new]
+    #[serde(skip)]
+    pub prompt: String,
+    /// `matching.conjunctions`, filled in by [Config::new]
+    #[serde(skip)]
+    pub conjunctions: Vec<String>,
+}
+
+impl Whisper {
+    fn default_best_of() -> i32 {
+        return 1;
+    }
+
+    fn default_beam_size() -> i32 {
+        return 5;
+    }
+
+    fn default_patience() -> f32 {
+        return -1.0;
+    }
+
+    fn default_language() -> String {
+        return "en".to_string();
+    }
+
+    fn default_vocabulary_bias() -> f32 {
+        return 5.0;
+    }
+
+    fn default_max_no_speech() -> f32 {
+        return 1.0;
+    }
+
+    /// Checks that the values can be given to whisper, returns every problem found
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        match self.strategy {
+            DecodingStrategy::Greedy if self.best_of < 1 => {
+                problems.push("whisper.best_of must be at least 1".to_string())
+            }
+            DecodingStrategy::BeamSearch if self.beam_size < 1 => {
+                problems.push("whisper.beam_size must be at least 1".to_string())
+            }
+            _ => {}
+        }
+        if self.threads.is_some_and(|x| x < 1) {
+            problems.push("whisper.threads must be at least 1".to_string());
+        }
+        if self.temperature.is_some_and(|x| !(0.0..=1.0).contains(&x)) {
+            problems.push("whisper.temperature must be between 0.0 and 1.0".to_string());
+        }
+        if self.temperature_inc.is_some_and(|x| x < 0.0) {
+            problems.push("whisper.temperature_inc can not be negative".to_string());
+        }
+        if self
+            .no_speech_thold
+            .is_some_and(|x| !(0.0..=1.0).contains(&x))
+        {
+            problems.push("whisper.no_speech_thold must be between 0.0 and 1.0".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.min_confidence) {
+            problems.push("whisper.min_confidence must be between 0.0 and 1.0".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.max_no_speech) {
+            problems.push("whisper.max_no_speech must be between 0.0 and 1.0".to_string());
+        }
+        if self.vocabulary_bias < 0.0 {
+            problems.push("whisper.vocabulary_bias can not be negative".to_string());
+        }
+        if self.max_tokens.is_some_and(|x| x < 0) {
+            problems.push("whisper.max_tokens can not be negative".to_string());
+        }
+        if self.language != AUTO_LANGUAGE && whisper_rs::get_lang_id(&self.language).is_none() {
+            problems.push(format!(
+                "whisper.language '{}' is not a language whisper knows",
+                self.language
+            ));
+        }
+        return problems;
+    }
 }
 
 #[derive(Deserialize, Debug)]
 /// Profile for the commands
 pub struct Profile {
     pub record_keybind: KeybdKey,
+    /// re-reads the profile file, changes to `record_keybind` still need a restart
+    pub reload_keybind: Option<KeybdKey>,
+    /// model used instead of `--model-path`, reloading the profile switches to it\
+    /// relative paths start at the directory of the profile file
+    pub model: Option<String>,
     pub commands: Vec<Command>,
     pub whisper: Whisper,
+    /// silence trimming and the "no speech" gate before transcription
+    #[serde(default)]
+    pub trim: TrimSettings,
+    /// voice activity detection for `--open-mic`
+    #[serde(default)]
+    pub vad: VadSettings,
+    /// with `--open-mic` only utterances starting with the wake word trigger commands
+    pub wake_word: Option<WakeWord>,
+    /// partial transcripts while the record key is held
+    #[serde(default)]
+    pub streaming: StreamingSettings,
+    /// how transcripts that are not exactly a command name are matched
+    #[serde(default)]
+    pub matching: MatchingSettings,
 }
 
 impl Profile {
-    pub fn new(args: &CommandArguments) -> Self {
-        let file_contents = fs::read_to_string(&args.profile_path).expect("Unable to read file");
-        let mut parsed: Self = toml::from_str(&file_contents).expect("Unable to parse TOML");
-        // TODO: enforce that parsed.commands[i].modifiers are unique
-        //  goal is to remove duplicate "leftcommand" and the likes
-        //  not really a priority however as I expect the user not to do that
+    pub fn new(args: &CommandArguments) -> Result<Self, anyhow::Error> {
+        let file_contents = fs::read_to_string(&args.profile_path)
+            .with_context(|| format!("could not read profile '{}'", args.profile_path))?;
+        return Self::parse(&file_contents, &args.profile_path);
+    }
+
+    /// Parses and validates the contents of a profile file, `path` is only used in errors
+    pub fn parse(file_contents: &str, path: &str) -> Result<Self, anyhow::Error> {
+        let mut parsed: Self = toml::from_str(file_contents)
+            .with_context(|| format!("could not parse profile '{}'", path))?;
+        let mut problems = parsed.whisper.validate();
+        problems.extend(parsed.vad.validate());
+        problems.extend(parsed.trim.validate());
+        problems.extend(parsed.streaming.validate());
+        problems.extend(parsed.matching.validate());
+        if let Some(wake_word) = &parsed.wake_word {
+            problems.extend(wake_word.validate());
+        }
+        problems.extend(
+            validate_commands(file_contents)
+                .iter()
+                .map(ToString::to_string),
+        );
+        if !problems.is_empty() {
+            bail!("invalid profile {}:\n  {}", path, problems.join("\n  "));
+        }
 
         // not actually sure if the following is needed
         let nrgx = Regex::new(r"[\n]+").expect("regex required");
         parsed.whisper.initial_prompt = nrgx
             .replace_all(&parsed.whisper.initial_prompt, " ")
             .to_string();
+        if let Some(prompt) = parsed.whisper.prompt_override.as_mut() {
+            *prompt = nrgx.replace_all(prompt, " ").to_string();
+        }
 
-        return parsed;
+        return Ok(parsed);
+    }
+
+    /// `model` when the profile has one, otherwise `--model-path`
+    pub fn model_path(&self, args: &CommandArguments) -> String {
+        let model = match &self.model {
+            None => return args.model_path.clone(),
+            Some(x) => x,
+        };
+        let directory = Path::new(&args.profile_path)
+            .parent()
+            .unwrap_or(Path::new(""));
+        return directory.join(model).to_string_lossy().to_string();
     }
 }
 
@@ -78,42 +293,74 @@ impl Profile {
 #[derive(Debug)]
 pub struct Config {
     pub profile: Profile,
-    /// Initial prompt for whisper
-    // pub initial_prompt: String, // replaced by Profile.whisper.initial_prompt
     /// command map that contains indexes for profile.commands
     command_map: HashMap<String, usize>,
+    /// used when a transcript is not in `command_map`
+    matcher: FuzzyMatcher,
+    /// used when fuzzy matching found nothing either
+    pub phonetic: PhoneticIndex,
 }
 
 impl Config {
-    pub fn new(args: &CommandArguments) -> Self {
-        let profile = Profile::new(args);
-        let mut command_map: HashMap<String, usize> = HashMap::new();
-
-        // initialized with arbritary capacity
-        // let mut initial_prompt = String::with_capacity(64);
-        // initial_prompt.push_str("Glossary: ");
+    pub fn new(args: &CommandArguments) -> Result<Self, anyhow::Error> {
+        return Self::from_profile(Profile::new(args)?);
+    }
 
-        let prompt_rgx = PROMPT_REGEX.get().expect("regex required");
+    /// fails when two commands share a name, which [Profile::new] already reports with its
+    /// line, so this only happens for profiles that were not read from a file
+    pub fn from_profile(mut profile: Profile) -> Result<Self, anyhow::Error> {
+        let mut command_map: HashMap<String, usize> = HashMap::new();
 
         let commands_length = profile.commands.len();
         for command_index in 0..commands_length {
             let command = &profile.commands[command_index];
+            for name in command.names() {
+                let processed_name = normalize_prompt(name);
+                match command_map.insert(processed_name, command_index) {
+                    Some(other) if other != command_index => bail!(
+                        "'{}' is already a name of command '{}'",
+                        name,
+                        profile.commands[other].name
+                    ),
+                    _ => {}
+                }
+            }
+        }
 
-            // // TODO: add only unique words
-            // initial_prompt.push_str(&command.name);
-            // if command_index != commands_length - 1 {
-            //     initial_prompt.push_str(", ")
-            // }
+        profile.whisper.vocabulary = profile
+            .commands
+            .iter()
+            .flat_map(|x| x.names())
+            .map(String::clone)
+            .collect();
+        profile.whisper.prompt = build_prompt(&profile.whisper);
+        profile.whisper.conjunctions = profile.matching.conjunctions.clone();
+        speech_to_text::check_language(&profile.whisper.language);
 
-            let processed_name = prompt_rgx.replace_all(&command.name, "").to_lowercase();
-            command_map.insert(processed_name, command_index);
-        }
+        let names: Vec<(&String, usize)> = profile
+            .commands
+            .iter()
+            .enumerate()
+            .flat_map(|(index, command)| command.names().map(move |name| (name, index)))
+            .collect();
+        let matcher = FuzzyMatcher::new(names.iter().copied(), &profile.matching);
+        let phonetic = PhoneticIndex::new(names.iter().copied());
 
-        return Self {
+        return Ok(Self {
             profile,
-            // initial_prompt,
             command_map,
-        };
+            matcher,
+            phonetic,
+        });
+    }
+
+    /// Switches to the model the profile asks for, or back to `--model-path` when it asks
+    /// for none. The prompt is rebuilt for the new model
+    pub fn apply_model(&mut self, args: &CommandArguments) {
+        if speech_to_text::switch_model(&self.profile.model_path(args)) {
+            self.profile.whisper.prompt = build_prompt(&self.profile.whisper);
+            speech_to_text::check_language(&self.profile.whisper.language);
+        }
     }
 
     pub fn get_command(&self, command_name: &str) -> Option<&Command> {
@@ -124,4 +371,259 @@ impl Config {
             Some(x) => return Some(&self.profile.commands[*x]),
         }
     }
+
+    /// Finds the command for a transcript, `text` does not have to be normalized yet\
+    /// exact names are looked up first, then fuzzy matching and matching by sound are tried
+    pub fn match_command(&self, text: &str) -> Option<&Command> {
+        if let Some(command) = self.get_command(&normalize_prompt(text)) {
+            return Some(command);
+        }
+        let index = self
+            .matcher
+            .find(text)
+            .or_else(|| self.phonetic.find(text))?;
+        return Some(&self.profile.commands[index]);
+    }
+
+    /// Finds every command in a transcript that may name several, with the part of the
+    /// transcript each was found in, in the order they were said.
+    /// A transcript that is exactly one command name is never split
+    pub fn match_commands(&self, text: &str) -> Vec<(String, Option<&Command>)> {
+        if let Some(command) = self.get_command(&normalize_prompt(text)) {
+            return vec![(text.to_string(), Some(command))];
+        }
+
+        let segments = split_utterance(text, &self.profile.matching.conjunctions);
+        if segments.len() < 2 {
+            return vec![(text.to_string(), self.match_command(text))];
+        }
+        return segments
+            .into_iter()
+            .map(|x| {
+                let command = self.match_command(&x);
+                (x, command)
+            })
+            .collect();
+    }
+
+    /// Whether a normalized word is a whole command name or the first word of one,
+    /// names glued to the wake word are only accepted when this holds
+    pub fn starts_command(&self, word: &str) -> bool {
+        return self
+            .profile
+            .commands
+            .iter()
+            .flat_map(|x| x.names())
+            .any(|name| {
+                normalize_prompt(name) == word
+                    || normalize_words(name).first().is_some_and(|x| x == word)
+            });
+    }
+
+    /// Same as [Self::get_command] but `None` when another command's name starts with
+    /// `command_name`, as more speech could still turn it into that command
+    pub fn get_unambiguous_command(&self, command_name: &str) -> Option<&Command> {
+        let index = self.command_map.get(command_name)?;
+        let ambiguous = self
+            .command_map
+            .iter()
+            .any(|(name, x)| x != index && name.starts_with(command_name));
+        if ambiguous {
+            return None;
+        }
+        return Some(&self.profile.commands[*index]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    const COMMANDS: &str = r#"
+record_keybind = "numpad1"
+
+[whisper]
+initial_prompt = ""
+
+[[commands]]
+name = "resupply"
+action = "swsa"
+"#;
+
+    fn args(profile_path: &str) -> CommandArguments {
+        return CommandArguments::parse_from([
+            "vox-strike",
+            "--profile-path",
+            profile_path,
+            "--model-path",
+            "models/ggml-base.en.bin",
+        ]);
+    }
+
+    fn profile(toml: &str) -> Profile {
+        return Profile::parse(toml, "test profile").expect("valid profile required");
+    }
+
+    fn config(toml: &str) -> Config {
+        return Config::from_profile(profile(toml)).expect("valid profile required");
+    }
+
+    #[test]
+    fn model_falls_back_to_model_path() {
+        let profile = profile(COMMANDS);
+        let args = args("profiles/helldivers2.toml");
+        assert_eq!(profile.model_path(&args), "models/ggml-base.en.bin");
+    }
+
+    #[test]
+    fn relative_model_starts_at_the_profile() {
+        let toml = format!("model = \"ggml-small.en.bin\"\n{}", COMMANDS);
+        let profile = profile(&toml);
+
+        let model = profile.model_path(&args("profiles/helldivers2.toml"));
+        assert_eq!(Path::new(&model), Path::new("profiles/ggml-small.en.bin"));
+        // a profile in the working directory has no parent to start at
+        assert_eq!(
+            profile.model_path(&args("helldivers2.toml")),
+            "ggml-small.en.bin"
+        );
+    }
+
+    const EAGLE_AIRSTRIKE: &str = r#"
+[[commands]]
+name = "eagle airstrike"
+action = "wdsd"
+aliases = ["air strike"]
+"#;
+
+    const ORBITAL_AIRSTRIKE: &str = r#"
+[[commands]]
+name = "orbital airstrike"
+action = "dsdw"
+aliases = ["airstrike"]
+"#;
+
+    #[test]
+    fn shared_alias_is_a_load_error() {
+        let toml = format!("{}{}{}", COMMANDS, EAGLE_AIRSTRIKE, ORBITAL_AIRSTRIKE);
+        let path =
+            std::env::temp_dir().join(format!("vox-strike-{}-alias.toml", std::process::id()));
+        fs::write(&path, &toml).expect("writable temp directory required");
+        let result = Config::new(&args(&path.to_string_lossy()));
+        fs::remove_file(&path).expect("removable temp file required");
+
+        let error = format!("{:#}", result.expect_err("a load error"));
+        assert!(
+            error.contains("'airstrike' is already a name of command 'eagle airstrike'"),
+            "{}",
+            error
+        );
+
+        // the same check without the validator of the profile file
+        let mut combined = profile(&format!("{}{}", COMMANDS, EAGLE_AIRSTRIKE));
+        let orbital = profile(&format!("{}{}", COMMANDS, ORBITAL_AIRSTRIKE));
+        combined
+            .commands
+            .extend(orbital.commands.into_iter().skip(1));
+        let error = Config::from_profile(combined).expect_err("a load error");
+        assert!(error.to_string().contains("'airstrike'"), "{}", error);
+    }
+
+    #[test]
+    fn prefix_of_another_name_is_ambiguous() {
+        let toml = format!(
+            "{}\n{}",
+            COMMANDS,
+            r#"
+[[commands]]
+name = "eagle"
+action = "wdsd"
+
+[[commands]]
+name = "eagle airstrike"
+action = "wdsdw"
+"#
+        );
+        let config = config(&toml);
+
+        // more speech could still turn "eagle" into "eagle airstrike"
+        assert!(config.get_command("eagle").is_some());
+        assert!(config.get_unambiguous_command("eagle").is_none());
+        let command = config.get_unambiguous_command("eagleairstrike");
+        assert_eq!(command.map(|x| x.name.as_str()), Some("eagle airstrike"));
+        let command = config.get_unambiguous_command("resupply");
+        assert_eq!(command.map(|x| x.name.as_str()), Some("resupply"));
+        assert!(config.get_unambiguous_command("eagleair").is_none());
+    }
+
+    const SEVERAL_COMMANDS: &str = r#"
+[[commands]]
+name = "reinforce"
+action = "wsdaw"
+
+[[commands]]
+name = "search and destroy"
+action = "sdws"
+"#;
+
+    /// each part of the transcript with the name of the command found in it
+    fn matched(config: &Config, text: &str) -> Vec<(String, Option<String>)> {
+        return config
+            .match_commands(text)
+            .into_iter()
+            .map(|(part, command)| (part, command.map(|x| x.name.clone())))
+            .collect();
+    }
+
+    fn part(text: &str, command: &str) -> (String, Option<String>) {
+        return (text.to_string(), Some(command.to_string()));
+    }
+
+    #[test]
+    fn several_commands_are_matched_in_order() {
+        let config = config(&format!("{}{}", COMMANDS, SEVERAL_COMMANDS));
+
+        assert_eq!(
+            matched(&config, "resupply and reinforce"),
+            [part("resupply", "resupply"), part("reinforce", "reinforce")]
+        );
+        assert_eq!(
+            matched(&config, "reinforce then resupply"),
+            [part("reinforce", "reinforce"), part("resupply", "resupply")]
+        );
+        assert_eq!(
+            matched(&config, "resupply, reinforce"),
+            [part("resupply", "resupply"), part("reinforce", "reinforce")]
+        );
+        // an exact name is never split on the conjunction in it
+        assert_eq!(
+            matched(&config, "Search and destroy!"),
+            [part("Search and destroy!", "search and destroy")]
+        );
+    }
+
+    #[test]
+    fn no_conjunctions_never_split() {
+        let toml = format!(
+            "{}{}\n[matching]\nconjunctions = []\n",
+            COMMANDS, SEVERAL_COMMANDS
+        );
+        let config = config(&toml);
+
+        let parts = matched(&config, "resupply and reinforce");
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].0, "resupply and reinforce");
+        let parts = matched(&config, "resupply, reinforce");
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].0, "resupply, reinforce");
+    }
+
+    #[test]
+    fn absolute_model_is_kept() {
+        let absolute = std::env::temp_dir().join("ggml-small.en.bin");
+        let toml = format!("model = '{}'\n{}", absolute.display(), COMMANDS);
+        let model = profile(&toml).model_path(&args("profiles/helldivers2.toml"));
+        assert_eq!(Path::new(&model), absolute);
+    }
 }
@@ -0,0 +1,286 @@
+/// Sample rate conversion for captured audio
+///
+/// whisper.cpp only understands 16 kHz mono, while most input devices default to 44.1 or 48 kHz.
+/// This is a band-limited (windowed sinc) resampler that works on a whole utterance at once,
+/// which is all we need since audio is only transcribed after the record key is released.
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::f64::consts::PI;
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResampleQuality {
+    /// Linear interpolation -- cheapest, lets some aliasing through
+    Linear,
+    /// Windowed sinc with a short kernel
+    #[default]
+    Medium,
+    /// Windowed sinc with a long kernel -- sharpest cutoff, slowest
+    High,
+}
+
+impl ResampleQuality {
+    /// amount of sinc zero crossings on each side of the kernel
+    fn zero_crossings(&self) -> usize {
+        match self {
+            ResampleQuality::Linear => 0,
+            ResampleQuality::Medium => 16,
+            ResampleQuality::High => 64,
+        }
+    }
+}
+
+/// Resamples mono `audio` from `from_rate` to `to_rate`
+pub fn resample(audio: &[f32], from_rate: u32, to_rate: u32, quality: ResampleQuality) -> Vec<f32> {
+    if from_rate == to_rate || audio.is_empty() {
+        return audio.to_vec();
+    }
+
+    // position step in input samples per output sample
+    let step = from_rate as f64 / to_rate as f64;
+    let output_length = (audio.len() as f64 / step).floor() as usize;
+
+    return match quality {
+        ResampleQuality::Linear => resample_linear(audio, step, output_length),
+        _ => resample_sinc(audio, step, output_length, quality.zero_crossings()),
+    };
+}
+
+fn resample_linear(audio: &[f32], step: f64, output_length: usize) -> Vec<f32> {
+    let last = audio.len() - 1;
+    let mut output = Vec::with_capacity(output_length);
+
+    for n in 0..output_length {
+        let position = n as f64 * step;
+        let index = position.floor() as usize;
+        let fraction = (position - index as f64) as f32;
+
+        let current = audio[index.min(last)];
+        let next = audio[(index + 1).min(last)];
+        output.push(current + (next - current) * fraction);
+    }
+
+    return output;
+}
+
+fn resample_sinc(
+    audio: &[f32],
+    step: f64,
+    output_length: usize,
+    zero_crossings: usize,
+) -> Vec<f32> {
+    // when downsampling the cutoff has to move below the new nyquist frequency
+    let cutoff = (1.0 / step).min(1.0);
+    // kernel half width in input samples
+    let half_width = zero_crossings as f64 / cutoff;
+    let kernel = KernelTable::new(zero_crossings);
+    let last = audio.len() as i64 - 1;
+    let mut output = Vec::with_capacity(output_length);
+
+    for n in 0..output_length {
+        let position = n as f64 * step;
+        let first_tap = ((position - half_width).ceil() as i64).max(0);
+        let last_tap = ((position + half_width).floor() as i64).min(last);
+
+        let mut sum = 0.0;
+        let mut weight_sum = 0.0;
+        for k in first_tap..=last_tap {
+            let weight = kernel.get(cutoff * (position - k as f64));
+            sum += audio[k as usize] as f64 * weight;
+            weight_sum += weight;
+        }
+
+        // normalizing keeps unity gain near the edges of the buffer where the kernel is cut off
+        let sample = if weight_sum.abs() > f64::EPSILON {
+            sum / weight_sum
+        } else {
+            sum
+        };
+        output.push(sample as f32);
+    }
+
+    return output;
+}
+
+/// Precomputed windowed sinc, evaluating sin/cos for every tap is far too slow
+struct KernelTable {
+    values: Vec<f64>,
+    zero_crossings: f64,
+}
+
+impl KernelTable {
+    /// table entries per zero crossing
+    const OVERSAMPLING: usize = 512;
+
+    fn new(zero_crossings: usize) -> Self {
+        let length = zero_crossings * Self::OVERSAMPLING + 1;
+        let values = (0..length)
+            .map(|i| {
+                let x = i as f64 / Self::OVERSAMPLING as f64;
+                sinc(x) * blackman(x / zero_crossings as f64)
+            })
+            .collect();
+
+        return Self {
+            values,
+            zero_crossings: zero_crossings as f64,
+        };
+    }
+
+    /// kernel value at `x` zero crossings away from the center, linearly interpolated
+    fn get(&self, x: f64) -> f64 {
+        let x = x.abs();
+        if x >= self.zero_crossings {
+            return 0.0;
+        }
+
+        let position = x * Self::OVERSAMPLING as f64;
+        let index = position as usize;
+        let fraction = position - index as f64;
+        let current = self.values[index];
+        let next = self.values.get(index + 1).copied().unwrap_or(0.0);
+        return current + (next - current) * fraction;
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < f64::EPSILON {
+        return 1.0;
+    }
+    let px = PI * x;
+    return px.sin() / px;
+}
+
+/// Blackman window over `x` in [-1, 1]
+fn blackman(x: f64) -> f64 {
+    if x.abs() > 1.0 {
+        return 0.0;
+    }
+    let phase = PI * (x + 1.0);
+    return 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATES: [u32; 4] = [22050, 44100, 48000, 96000];
+    const QUALITIES: [ResampleQuality; 3] = [
+        ResampleQuality::Linear,
+        ResampleQuality::Medium,
+        ResampleQuality::High,
+    ];
+    const TARGET_RATE: u32 = 16000;
+
+    fn tone(frequency: f64, sample_rate: u32, seconds: f64) -> Vec<f32> {
+        let length = (seconds * sample_rate as f64) as usize;
+        return (0..length)
+            .map(|n| (2.0 * PI * frequency * n as f64 / sample_rate as f64).sin() as f32)
+            .collect();
+    }
+
+    /// linear sweep from `start` to `end` Hz
+    fn sweep(start: f64, end: f64, sample_rate: u32, seconds: f64) -> Vec<f32> {
+        let length = (seconds * sample_rate as f64) as usize;
+        let rate = (end - start) / seconds;
+        return (0..length)
+            .map(|n| {
+                let t = n as f64 / sample_rate as f64;
+                (2.0 * PI * (start * t + rate * t * t / 2.0)).sin() as f32
+            })
+            .collect();
+    }
+
+    /// RMS without the edges, where the kernel runs out of input
+    fn rms_middle(audio: &[f32]) -> f64 {
+        let edge = audio.len() / 10;
+        let middle = &audio[edge..audio.len() - edge];
+        let power: f64 = middle.iter().map(|x| (*x as f64).powi(2)).sum();
+        return (power / middle.len() as f64).sqrt();
+    }
+
+    #[test]
+    fn output_length_follows_the_rate_ratio() {
+        for rate in RATES {
+            for quality in QUALITIES {
+                let input = tone(440.0, rate, 1.0);
+                let output = resample(&input, rate, TARGET_RATE, quality);
+                assert_eq!(
+                    output.len(),
+                    TARGET_RATE as usize,
+                    "{} Hz {:?}",
+                    rate,
+                    quality
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn same_rate_is_unchanged() {
+        let input = tone(440.0, TARGET_RATE, 0.1);
+        let output = resample(&input, TARGET_RATE, TARGET_RATE, ResampleQuality::High);
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn passband_tone_keeps_its_gain() {
+        for rate in RATES {
+            for quality in QUALITIES {
+                let input = tone(1000.0, rate, 1.0);
+                let output = resample(&input, rate, TARGET_RATE, quality);
+                let gain = rms_middle(&output) / rms_middle(&input);
+                assert!(
+                    (gain - 1.0).abs() < 0.05,
+                    "{} Hz {:?}: gain {}",
+                    rate,
+                    quality,
+                    gain
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn passband_sweep_keeps_its_gain() {
+        for rate in RATES {
+            for quality in [ResampleQuality::Medium, ResampleQuality::High] {
+                let input = sweep(100.0, 6000.0, rate, 2.0);
+                let output = resample(&input, rate, TARGET_RATE, quality);
+                let gain = rms_middle(&output) / rms_middle(&input);
+                assert!(
+                    (gain - 1.0).abs() < 0.05,
+                    "{} Hz {:?}: gain {}",
+                    rate,
+                    quality,
+                    gain
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn content_above_8_khz_is_attenuated() {
+        for rate in RATES {
+            // has to stay below the nyquist frequency of the input
+            let frequency = if rate < 24000 { 10000.0 } else { 12000.0 };
+            let input = tone(frequency, rate, 1.0);
+            let mut gains = Vec::new();
+            for quality in QUALITIES {
+                let output = resample(&input, rate, TARGET_RATE, quality);
+                gains.push(rms_middle(&output) / rms_middle(&input));
+            }
+            let [linear, medium, high] = gains[..] else {
+                unreachable!()
+            };
+            assert!(medium < 0.05, "{} Hz Medium: gain {}", rate, medium);
+            assert!(high < 0.01, "{} Hz High: gain {}", rate, high);
+            assert!(
+                high <= medium && medium < linear,
+                "{} Hz: {:?}",
+                rate,
+                gains
+            );
+        }
+    }
+}
@@ -0,0 +1,226 @@
+/// Bounded single-producer/single-consumer sample buffer
+///
+/// The producer is the cpal audio callback which runs on a real-time thread,
+/// so pushing must never allocate, lock or wait on the consumer.
+use clap::ValueEnum;
+use std::sync::atomic::{fence, AtomicU32, AtomicUsize, Ordering};
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Overwrite the oldest samples that have not been read yet
+    #[default]
+    DropOldest,
+    /// Discard new samples until there is room again
+    Stop,
+}
+
+/// Samples are stored as f32 bits in atomics so that the producer overwriting a slot
+/// the consumer is reading (with [OverflowPolicy::DropOldest]) is not a data race.
+/// The consumer notices that case because `head` moved and retries.
+pub struct RingBuffer {
+    slots: Box<[AtomicU32]>,
+    policy: OverflowPolicy,
+    /// total amount of samples read (or skipped because of overflow)
+    head: AtomicUsize,
+    /// total amount of samples written
+    tail: AtomicUsize,
+    /// samples lost to overflow
+    dropped: AtomicUsize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        let capacity = capacity.max(1);
+        let slots = (0..capacity).map(|_| AtomicU32::new(0)).collect();
+
+        return Self {
+            slots,
+            policy,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        };
+    }
+
+    pub fn capacity(&self) -> usize {
+        return self.slots.len();
+    }
+
+    /// amount of samples lost to overflow since the last call, restarts counting
+    pub fn take_dropped(&self) -> usize {
+        return self.dropped.swap(0, Ordering::Relaxed);
+    }
+
+    /// Producer side -- must only be called from one thread at a time\
+    /// returns the amount of samples written
+    pub fn push_slice(&self, data: &[f32]) -> usize {
+        let capacity = self.capacity();
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        let free = capacity - (tail - head).min(capacity);
+
+        let mut data = data;
+        if data.len() > free {
+            match self.policy {
+                OverflowPolicy::Stop => {
+                    self.dropped.fetch_add(data.len() - free, Ordering::Relaxed);
+                    data = &data[..free];
+                }
+                OverflowPolicy::DropOldest => {
+                    // only the newest `capacity` samples can be kept
+                    if data.len() > capacity {
+                        let skipped = data.len() - capacity;
+                        self.dropped.fetch_add(skipped, Ordering::Relaxed);
+                        data = &data[skipped..];
+                    }
+
+                    // move the consumer past the samples that are about to be overwritten
+                    let new_head = tail + data.len() - capacity;
+                    let previous = self.head.fetch_max(new_head, Ordering::AcqRel);
+                    self.dropped
+                        .fetch_add(new_head.saturating_sub(previous), Ordering::Relaxed);
+                    // orders the head update before the slot writes, see `pop_into`
+                    fence(Ordering::Release);
+                }
+            }
+        }
+
+        for (offset, sample) in data.iter().enumerate() {
+            self.slots[(tail + offset) % capacity].store(sample.to_bits(), Ordering::Relaxed);
+        }
+        self.tail.store(tail + data.len(), Ordering::Release);
+
+        return data.len();
+    }
+
+    /// Consumer side -- must only be called from one thread at a time\
+    /// appends every available sample to `output`, returns the amount of samples read
+    pub fn pop_into(&self, output: &mut Vec<f32>) -> usize {
+        let capacity = self.capacity();
+        let original_length = output.len();
+
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            let tail = self.tail.load(Ordering::Acquire);
+            if tail <= head {
+                return 0;
+            }
+
+            output.truncate(original_length);
+            output.extend(
+                (head..tail)
+                    .map(|i| f32::from_bits(self.slots[i % capacity].load(Ordering::Relaxed))),
+            );
+
+            // if the producer overwrote anything we read then it has also moved head,
+            // which makes this fail so the read is retried from the new head
+            fence(Ordering::Acquire);
+            if self
+                .head
+                .compare_exchange(head, tail, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return tail - head;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    /// small so that the producer keeps catching up with the consumer
+    const CAPACITY: usize = 256;
+    /// f32 represents every integer up to 2^24 exactly
+    const SAMPLES: usize = 1 << 20;
+
+    /// chunk sizes that vary like audio callbacks do, some larger than the buffer
+    fn chunk_length(chunk: usize) -> usize {
+        return 1 + (chunk * 7919) % (CAPACITY + CAPACITY / 2);
+    }
+
+    /// Feeds `0..SAMPLES` from one thread while another drains, like the audio callback and
+    /// the record keybind do. Returns what was read and how many samples were rejected by
+    /// `push_slice` (those are pushed again when `retry` is set)
+    fn run(policy: OverflowPolicy, retry: bool) -> (Vec<f32>, usize, Arc<RingBuffer>) {
+        let buffer = Arc::new(RingBuffer::new(CAPACITY, policy));
+
+        let producer_buffer = buffer.clone();
+        let producer = thread::spawn(move || {
+            let samples: Vec<f32> = (0..SAMPLES).map(|x| x as f32).collect();
+            let mut rejected = 0;
+            let mut position = 0;
+            let mut chunk = 0;
+            while position < SAMPLES {
+                let end = (position + chunk_length(chunk)).min(SAMPLES);
+                chunk += 1;
+                let written = producer_buffer.push_slice(&samples[position..end]);
+                rejected += end - position - written;
+                if retry {
+                    position += written;
+                    if written == 0 {
+                        thread::yield_now();
+                    }
+                } else {
+                    position = end;
+                }
+            }
+            rejected
+        });
+
+        let mut output = Vec::with_capacity(SAMPLES);
+        let mut reads = 0;
+        loop {
+            let finished = producer.is_finished();
+            buffer.pop_into(&mut output);
+            if finished {
+                // everything pushed before finishing is visible now
+                buffer.pop_into(&mut output);
+                break;
+            }
+            reads += 1;
+            // a slow consumer now and then, so that the buffer overflows
+            if reads % 64 == 0 {
+                thread::yield_now();
+            }
+        }
+
+        let rejected = producer.join().unwrap();
+        return (output, rejected, buffer);
+    }
+
+    #[test]
+    fn stop_keeps_every_sample_in_order() {
+        let (output, rejected, buffer) = run(OverflowPolicy::Stop, true);
+
+        assert_eq!(output.len(), SAMPLES);
+        for (index, sample) in output.iter().enumerate() {
+            assert_eq!(*sample, index as f32, "gap or reordering at {}", index);
+        }
+        // samples rejected while full were counted as dropped, then pushed again
+        assert_eq!(buffer.take_dropped(), rejected);
+    }
+
+    #[test]
+    fn drop_oldest_is_monotonic_and_accounts_for_every_loss() {
+        // chunks larger than the buffer only get their newest samples written,
+        // the rest is counted as dropped like overwritten samples are
+        let (output, _, buffer) = run(OverflowPolicy::DropOldest, false);
+
+        for sample in &output {
+            assert!(
+                sample.fract() == 0.0 && (0.0..SAMPLES as f32).contains(sample),
+                "torn sample {}",
+                sample
+            );
+        }
+        for pair in output.windows(2) {
+            assert!(pair[0] < pair[1], "duplicate or reordering: {:?}", pair);
+        }
+        assert_eq!(output.len() + buffer.take_dropped(), SAMPLES);
+        // the newest samples always survive
+        assert_eq!(output.last(), Some(&((SAMPLES - 1) as f32)));
+    }
+}
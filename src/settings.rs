@@ -1,4 +1,6 @@
-use clap::Parser;
+use crate::resample::ResampleQuality;
+use crate::ring_buffer::OverflowPolicy;
+use clap::{Parser, Subcommand};
 
 // TODO: make this parse and save settings for the user (when GUI is made)
 //  - the profile that the user last used
@@ -16,28 +18,128 @@ pub fn init() {
 
 // -----------------------------------------------------------------------------
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(version, about = "VoxStrike command arguments", long_about = None)]
 pub struct CommandArguments {
     // could be useful when writing linux support
     // https://github.com/RustAudio/cpal/blob/master/examples/record_wav.rs#L31
     //
-    /// Name of the audio input device to use
+    /// Name of the audio input device to use\
+    /// Valid names can be found with the `list-devices` subcommand
     #[arg(short, long, default_value_t = String::from("default"))]
     pub audio_in: String,
 
     /// Path to whisper model
     /// see https://github.com/ggerganov/whisper.cpp/blob/master/models/README.md
-    #[arg(short, long, default_value_t = String::from("ggml-base.en.bin"))]
+    #[arg(short, long, global = true, default_value_t = String::from("ggml-base.en.bin"))]
     pub model_path: String,
 
-    #[arg(short, long, default_value_t = String::from("profiles/helldivers2.toml"))]
+    #[arg(
+        short,
+        long,
+        global = true,
+        visible_alias = "profile",
+        default_value_t = String::from("profiles/helldivers2.toml")
+    )]
     pub profile_path: String,
 
     /// The delay (in milliseconds) between each key press in `profiles.commands[i].action`\
     /// Feel free to increase this if key presses are not picked up or decrease to make them faster
     #[arg(short, long, default_value_t = 69)]
     pub key_delay: u64,
+
+    /// Only use this channel (starting from 1) of the audio input device\
+    /// By default all channels are averaged into one
+    #[arg(short, long, global = true)]
+    pub input_channel: Option<u16>,
+
+    /// Quality of the resampler that converts captured audio to the 16 kHz whisper expects
+    #[arg(short, long, global = true, value_enum, default_value_t = ResampleQuality::Medium)]
+    pub resample_quality: ResampleQuality,
+
+    /// How much audio (in milliseconds) the input device can capture before it has to be
+    /// handed over for transcription
+    #[arg(short = 'b', long, default_value_t = 2000)]
+    pub capture_buffer_ms: u64,
+
+    /// What happens to captured audio when the capture buffer is full
+    #[arg(short, long, value_enum, default_value_t = OverflowPolicy::DropOldest)]
+    pub overflow_policy: OverflowPolicy,
+
+    /// Keep the input device open and prepend this much audio (in milliseconds) from before the
+    /// record key was pressed, so the first syllable is not lost to device start-up.\
+    /// Audio is only kept in memory and only for this window. 0 disables it, otherwise it should
+    /// be above 50 as the window also has to hold audio between buffer drains while recording
+    #[arg(long, default_value_t = 0)]
+    pub pre_roll_ms: u64,
+
+    /// Hands-free mode, utterances are detected with voice activity detection
+    /// instead of holding `record_keybind`. Tune it with the `[vad]` table of the profile
+    #[arg(long)]
+    pub open_mic: bool,
+
+    /// How many whisper states are created and warmed up at start-up, at least 1.\
+    /// Utterances reuse these instead of allocating a new state every time
+    #[arg(long, global = true, default_value_t = 1)]
+    pub state_pool_size: usize,
+
+    /// Text file with one transcript per line, these are used in order instead of
+    /// running whisper. Useful for trying out a profile without a model
+    #[arg(long, global = true)]
+    pub scripted_stt: Option<String>,
+
+    /// Runs the voice macros when no subcommand is given
+    #[command(subcommand)]
+    pub command: Option<SubCommand>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum SubCommand {
+    /// Transcribe a WAV file and print the command it would trigger, nothing is executed
+    Transcribe {
+        /// Path to the WAV file
+        #[arg(short, long)]
+        file: String,
+    },
+    /// List audio hosts, their input devices and supported stream configs
+    ListDevices {
+        /// Print as JSON instead
+        #[arg(long)]
+        json: bool,
+    },
+    /// Inspect, verify and benchmark ggml whisper models
+    Model {
+        #[command(subcommand)]
+        command: ModelCommand,
+    },
+    /// Print the phonetic keys of the profile's command names and which of them collide
+    PhoneticKeys,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ModelCommand {
+    /// Print the model type, size, language support and quantization
+    Info {
+        /// Path to the model, `--model-path` when left out
+        path: Option<String>,
+    },
+    /// Compare the SHA-256 of a model against a manifest
+    Verify {
+        /// Path to the model, `--model-path` when left out
+        path: Option<String>,
+        /// `sha256sum` output listing the expected hashes by file name
+        #[arg(long, default_value_t = String::from("models.sha256"))]
+        manifest: String,
+    },
+    /// Measure transcription latency on built-in synthetic audio,
+    /// with the `[whisper]` settings of the profile
+    Bench {
+        /// Path to the model, `--model-path` when left out
+        path: Option<String>,
+        /// How many times the audio is transcribed
+        #[arg(long, default_value_t = 5)]
+        runs: usize,
+    },
 }
 
 impl CommandArguments {
@@ -1,54 +1,298 @@
 /// copied and slightly modified from
 /// https://github.com/scripty-bot/stt-service/blob/53b688bf58ea31b566e250a4a32110403c93a9bf/stts_speech_to_text/src/lib.rs
-use log::{error, info};
-use parking_lot::Mutex;
+use crate::downmix::{downmix, Downmix};
+use crate::model::ModelInfo;
+use crate::profiles::{DecodingStrategy, Whisper};
+use crate::resample::{resample, ResampleQuality};
+use crate::ring_buffer::{OverflowPolicy, RingBuffer};
+use crate::stt_engine::{Segment, SttEngine, Token, Transcript};
+use crate::trim::{trim_silence, TrimSettings};
+use crate::vocabulary::VocabularyTrie;
+use anyhow::anyhow;
+use log::{debug, error, info, warn};
+use parking_lot::{const_rwlock, Condvar, Mutex, RwLock};
 use regex::Regex;
-use std::{fmt::Write, sync::OnceLock, time::Instant};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
+use unicode_normalization::UnicodeNormalization;
 pub use whisper_rs::*;
 
-pub static MODEL: OnceLock<WhisperContext> = OnceLock::new();
+/// whisper.cpp only works with 16 kHz mono audio
+pub const WHISPER_SAMPLE_RATE: u32 = 16000;
+/// `whisper.language` that lets whisper detect the spoken language
+pub const AUTO_LANGUAGE: &str = "auto";
+
+/// the model new transcriptions use, [load] replaces it
+static MODEL: RwLock<Option<Arc<LoadedModel>>> = const_rwlock(None);
 /// regex to unify prompt and command
 pub static PROMPT_REGEX: OnceLock<Regex> = OnceLock::new();
 
-pub fn load(model_path: &str) {
-    info!("attempting to load model");
-    let model = WhisperContext::new_with_params(model_path, WhisperContextParameters::new())
-        .expect("failed to load model");
-    MODEL.set(model).expect("failed to set models");
-    info!("loaded model");
-
-    PROMPT_REGEX
-        .set(Regex::new(r"[\W]+").expect("regex required"))
-        .expect("failed to set prompt regular expression");
-}
-
-fn get_new_model() -> Option<WhisperState<'static>> {
-    // if we got a model, return it
-    // on error, log it and return None
-    match MODEL.get().map(|ctx| ctx.create_state()) {
-        Some(Ok(state)) => Some(state),
-        Some(Err(e)) => {
-            error!("failed to create model: {:?}", e);
-            None
-        }
+/// Loads a model and makes it the one new transcriptions use\
+/// transcriptions that already started finish on the previous model, which is dropped after.
+/// When loading fails the previous model stays active\
+/// `pool_size` is the amount of whisper states that are created and warmed up ahead of time
+pub fn load(model_path: &str, pool_size: usize) -> Result<(), anyhow::Error> {
+    // whisper.cpp does not say why a model failed to load, the header check does
+    let model_info = ModelInfo::read(model_path)?;
+    info!(
+        "attempting to load {} ({}) model",
+        model_info.model_type(),
+        model_info.quantization()
+    );
+    let ctx = WhisperContext::new_with_params(model_path, WhisperContextParameters::new())
+        .map_err(|e| anyhow!("whisper.cpp could not load '{}': {:?}", model_path, e))?;
+    let model = Arc::new(LoadedModel::new(ctx, model_path, pool_size.max(1)));
+
+    match MODEL.write().replace(model) {
+        None => info!("loaded model {}", model_path),
+        Some(previous) => info!(
+            "[MODEL] switched from {} to {}, transcriptions in progress finish on the previous one",
+            previous.path, model_path
+        ),
+    }
+    return Ok(());
+}
+
+/// `None` until [load] succeeded
+pub fn current_model() -> Option<Arc<LoadedModel>> {
+    return MODEL.read().clone();
+}
+
+/// Loads `model_path` unless it is already the active model, returns whether it was switched.\
+/// Does nothing when no model was loaded at all (scripted transcripts)
+pub fn switch_model(model_path: &str) -> bool {
+    let current = match current_model() {
         None => {
-            error!("models not set up yet: check that load_model was called");
-            None
+            debug!(
+                "[MODEL] no model is loaded, not switching to {}",
+                model_path
+            );
+            return false;
+        }
+        Some(x) => x,
+    };
+    if current.path == model_path {
+        return false;
+    }
+
+    match load(model_path, current.pool_size) {
+        Ok(()) => return true,
+        Err(e) => {
+            error!(
+                "[MODEL] keeping {}, could not load {}: {:?}",
+                current.path, model_path, e
+            );
+            return false;
         }
     }
 }
 
-fn create_model_params(initial_prompt: &str) -> FullParams<'_, '_> {
+/// Warns when the active model can only transcribe english but `language` asks for another
+pub fn check_language(language: &str) {
+    let model = match current_model() {
+        None => return,
+        Some(x) => x,
+    };
+    if language != "en" && !model.ctx().is_multilingual() {
+        warn!(
+            "[MODEL] {} only knows english, whisper.language '{}' needs a multilingual model",
+            model.path, language
+        );
+    }
+}
+
+/// Unifies transcribed text so that it can be compared against command names\
+/// compatibility characters are unified (NFKC, full width letters become ASCII),
+/// accents are folded away (`é` becomes `e`) and everything but letters and numbers is removed
+pub fn normalize_prompt(text: &str) -> String {
+    // not set up in `load` as the scripted engine never loads a model
+    let rgx = PROMPT_REGEX.get_or_init(|| Regex::new(r"[\W]+").expect("regex required"));
+    let folded: String = text
+        .nfkc()
+        .flat_map(char::to_lowercase)
+        .collect::<String>()
+        .nfd()
+        .filter(|x| !is_accent(*x))
+        .nfc()
+        .collect();
+    return rgx.replace_all(&folded, "").to_string();
+}
+
+/// [normalize_prompt] word by word, for matching that cares about word boundaries
+pub fn normalize_words(text: &str) -> Vec<String> {
+    return text
+        .split_whitespace()
+        .map(normalize_prompt)
+        .filter(|x| !x.is_empty())
+        .collect();
+}
+
+/// Only the combining diacritical marks used with latin, greek and cyrillic letters,
+/// marks of other scripts (like the dakuten in `が`) change the letter instead of accenting it
+fn is_accent(c: char) -> bool {
+    return ('\u{0300}'..='\u{036f}').contains(&c);
+}
+
+/// A whisper model together with its pool of states
+pub struct LoadedModel {
+    pool: StatePool,
+    ctx: WhisperContext,
+    pub path: String,
+    pool_size: usize,
+}
+
+impl LoadedModel {
+    fn new(ctx: WhisperContext, path: &str, pool_size: usize) -> Self {
+        return Self {
+            pool: StatePool::warm_up(&ctx, pool_size),
+            ctx,
+            path: path.to_string(),
+            pool_size,
+        };
+    }
+
+    pub fn ctx(&self) -> &WhisperContext {
+        return &self.ctx;
+    }
+
+    /// states are reused since creating one allocates the KV cache and compute buffers\
+    /// waits until a state is available
+    fn take_state(self: &Arc<Self>) -> PooledState {
+        return PooledState {
+            state: Some(self.pool.take()),
+            model: self.clone(),
+        };
+    }
+}
+
+struct StatePool {
+    states: Mutex<Vec<WhisperState>>,
+    returned: Condvar,
+}
+
+impl StatePool {
+    /// Creates `size` states and runs a short inference on each of them,
+    /// the first inference is slower as it still has to allocate buffers
+    fn warm_up(ctx: &WhisperContext, size: usize) -> Self {
+        let silence = vec![0.0; WHISPER_SAMPLE_RATE as usize];
+        let mut states = Vec::with_capacity(size);
+        let mut create_duration = Duration::ZERO;
+
+        for i in 0..size {
+            let start = Instant::now();
+            let mut state = ctx.create_state().expect("failed to create whisper state");
+            create_duration += start.elapsed();
+
+            let start = Instant::now();
+            if let Err(e) = state.full(create_warm_up_params(), &silence) {
+                warn!("[STT] warm-up inference of state {} failed: {:?}", i, e);
+            }
+            debug!(
+                "[STT] warm-up inference of state {} took {} ms",
+                i,
+                start.elapsed().as_millis()
+            );
+            states.push(state);
+        }
+
+        info!(
+            "[STT] created {} whisper state(s) in {} ms",
+            size,
+            create_duration.as_millis()
+        );
+        return Self {
+            states: Mutex::new(states),
+            returned: Condvar::new(),
+        };
+    }
+
+    fn take(&self) -> WhisperState {
+        let mut states = self.states.lock();
+        loop {
+            if let Some(state) = states.pop() {
+                debug!("[STT] reusing a warm whisper state");
+                return state;
+            }
+            debug!("[STT] every whisper state is in use, waiting");
+            self.returned.wait(&mut states);
+        }
+    }
+}
+
+/// A state taken from a [LoadedModel], it goes back into the pool when dropped\
+/// holds on to the model so that the state still finds its pool after a model switch
+struct PooledState {
+    state: Option<WhisperState>,
+    model: Arc<LoadedModel>,
+}
+
+impl Deref for PooledState {
+    type Target = WhisperState;
+
+    fn deref(&self) -> &Self::Target {
+        return self.state.as_ref().expect("state is only taken on drop");
+    }
+}
+
+impl DerefMut for PooledState {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        return self.state.as_mut().expect("state is only taken on drop");
+    }
+}
+
+impl Drop for PooledState {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            self.model.pool.states.lock().push(state);
+            self.model.pool.returned.notify_one();
+        }
+    }
+}
+
+/// cheapest parameters, the warm-up result is thrown away
+fn create_warm_up_params() -> FullParams<'static, 'static> {
+    let mut wp = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+    wp.set_single_segment(true);
+    wp.set_no_context(true);
+    wp.set_print_progress(false);
+    wp.set_print_realtime(false);
+    wp.set_print_timestamps(false);
+    return wp;
+}
+
+fn create_model_params(whisper: &Whisper) -> FullParams<'_, '_> {
+    let strategy = match whisper.strategy {
+        DecodingStrategy::Greedy => SamplingStrategy::Greedy {
+            best_of: whisper.best_of,
+        },
+        DecodingStrategy::BeamSearch => SamplingStrategy::BeamSearch {
+            beam_size: whisper.beam_size,
+            patience: whisper.patience,
+        },
+    };
+
     // whisper parameters
-    let mut wp = FullParams::new(SamplingStrategy::BeamSearch {
-        beam_size: 5,
-        patience: -1.0,
-    });
-    wp.set_initial_prompt(initial_prompt);
-
-    // params.set_n_threads(1);
-    wp.set_language(Some("en"));
-    wp.set_suppress_non_speech_tokens(true);
+    let mut wp = FullParams::new(strategy);
+    wp.set_initial_prompt(&whisper.prompt);
+
+    if let Some(threads) = whisper.threads {
+        wp.set_n_threads(threads);
+    }
+    // "auto" makes whisper.cpp detect the language first
+    wp.set_language(Some(&whisper.language));
+    wp.set_suppress_nst(true);
+    if let Some(suppress_blank) = whisper.suppress_blank {
+        wp.set_suppress_blank(suppress_blank);
+    }
+    if let Some(no_speech_thold) = whisper.no_speech_thold {
+        wp.set_no_speech_thold(no_speech_thold);
+    }
+    if let Some(max_tokens) = whisper.max_tokens {
+        wp.set_max_tokens(max_tokens);
+    }
     // params.set_no_context(false);
     // since this is used for voice commands
     wp.set_single_segment(true);
@@ -58,8 +302,12 @@ fn create_model_params(initial_prompt: &str) -> FullParams<'_, '_> {
     wp.set_print_realtime(false);
     wp.set_print_timestamps(false);
 
-    // wp.set_temperature(0.4);
-    // wp.set_temperature_inc(1.0);
+    if let Some(temperature) = whisper.temperature {
+        wp.set_temperature(temperature);
+    }
+    if let Some(temperature_inc) = whisper.temperature_inc {
+        wp.set_temperature_inc(temperature_inc);
+    }
 
     return wp;
 }
@@ -67,104 +315,245 @@ fn create_model_params(initial_prompt: &str) -> FullParams<'_, '_> {
 // -----------------------------------------------------------------------------
 
 pub struct StreamFinishProperties<'a> {
-    pub verbose: bool,
-    pub initial_prompt: &'a str,
-    /// the amount of times to call whisper_rs::convert_stereo_to_mono_audio
-    pub halver_count: u16,
+    pub engine: Arc<dyn SttEngine>,
+    pub whisper: &'a Whisper,
+    /// amount of interleaved channels in the captured audio
+    pub channels: u16,
+    pub downmix: Downmix,
+    /// sample rate of the captured audio
+    pub sample_rate: u32,
+    pub resample_quality: ResampleQuality,
+    pub trim: &'a TrimSettings,
 }
 
 /// A wrapper around a Stream that holds the Stream on one thread constantly.
 pub struct SttStreamingState {
+    /// written to by the audio callback, so it can not lock or allocate
+    buffer: RingBuffer,
+    /// audio moved out of `buffer` by [Self::drain], never touched by the audio callback
     stream_data: Mutex<Vec<f32>>,
-    last_access: Mutex<Instant>,
-}
-
-impl Default for SttStreamingState {
-    fn default() -> Self {
-        Self::new()
-    }
 }
 
 impl SttStreamingState {
-    pub fn new() -> Self {
+    /// `capacity` is the amount of samples that can be fed before [Self::drain] has to be called
+    pub fn new(capacity: usize, overflow_policy: OverflowPolicy) -> Self {
         Self {
+            buffer: RingBuffer::new(capacity, overflow_policy),
             stream_data: Mutex::new(Vec::new()),
-            last_access: Mutex::new(Instant::now()),
         }
     }
 
-    pub fn feed_audio(&self, mut audio: Vec<f32>) {
-        self.stream_data.lock().append(&mut audio);
-        self.update_last_access();
+    /// safe to call from the real-time audio thread
+    pub fn feed_audio(&self, audio: &[f32]) {
+        self.buffer.push_slice(audio);
+    }
+
+    /// moves fed audio out of the ring buffer, call this regularly while recording
+    pub fn drain(&self) {
+        self.buffer.pop_into(&mut self.stream_data.lock());
+    }
+
+    /// Starts a new utterance on a stream that stays open between utterances\
+    /// whatever is still in the ring buffer is kept as pre-roll
+    pub fn start_utterance(&self) {
+        self.stream_data.lock().clear();
+        self.buffer.take_dropped();
+    }
+
+    /// Drains and copies up to the last `max_samples` captured so far without taking them,
+    /// also returns the amount of samples captured so far
+    pub fn peek_audio(&self, max_samples: usize) -> (Vec<f32>, usize) {
+        self.drain();
+        let stream_data = self.stream_data.lock();
+        let start = stream_data.len().saturating_sub(max_samples);
+        return (stream_data[start..].to_vec(), stream_data.len());
     }
 
-    pub fn finish_stream(self, properties: StreamFinishProperties) -> Result<String, WhisperError> {
-        let Self {
-            stream_data,
-            last_access: _,
-        } = self;
+    /// drops the first `samples` captured, they are not part of [Self::take_audio] anymore
+    pub fn discard_audio(&self, samples: usize) {
+        let mut stream_data = self.stream_data.lock();
+        let samples = samples.min(stream_data.len());
+        stream_data.drain(..samples);
+    }
 
-        // we own the stream data now, so we can drop the lock
-        let mut audio_data = stream_data.into_inner();
-        if audio_data.is_empty() {
-            return Ok(String::new());
+    /// drains and takes every sample captured so far
+    pub fn take_audio(&self) -> Vec<f32> {
+        self.drain();
+        let dropped = self.buffer.take_dropped();
+        if dropped > 0 {
+            warn!(
+                "[STT] audio buffer overflowed, {} samples were lost",
+                dropped
+            );
         }
 
-        // TODO: optimize this, maybe use the code inside
-        for _i in 0..properties.halver_count {
-            audio_data = whisper_rs::convert_stereo_to_mono_audio(&audio_data)
-                .expect("failed to convert audio data");
+        return std::mem::take(&mut *self.stream_data.lock());
+    }
+
+    /// `Ok(None)` when no speech was captured, see [transcribe]
+    pub fn finish_stream(
+        self,
+        properties: StreamFinishProperties,
+    ) -> Result<Option<Transcript>, anyhow::Error> {
+        return transcribe(self.take_audio(), properties);
+    }
+
+    /// Transcribes everything captured since [Self::start_utterance] without closing the stream
+    pub fn finish_utterance(
+        &self,
+        properties: StreamFinishProperties,
+    ) -> Result<Option<Transcript>, anyhow::Error> {
+        return transcribe(self.take_audio(), properties);
+    }
+}
+
+/// Runs the engine on captured audio, which is first converted to 16 kHz mono\
+/// returns `Ok(None)` without running the engine when there is no speech in the audio
+pub fn transcribe(
+    mut audio_data: Vec<f32>,
+    properties: StreamFinishProperties,
+) -> Result<Option<Transcript>, anyhow::Error> {
+    if audio_data.is_empty() {
+        return Ok(None);
+    }
+
+    if properties.channels > 1 {
+        audio_data = downmix(&audio_data, properties.channels, properties.downmix);
+    }
+
+    if properties.sample_rate != WHISPER_SAMPLE_RATE {
+        debug!(
+            "[STT] resampling {} samples from {} Hz to {} Hz ({:?})",
+            audio_data.len(),
+            properties.sample_rate,
+            WHISPER_SAMPLE_RATE,
+            properties.resample_quality
+        );
+        audio_data = resample(
+            &audio_data,
+            properties.sample_rate,
+            WHISPER_SAMPLE_RATE,
+            properties.resample_quality,
+        );
+    }
+
+    let captured_ms = audio_data.len() as u64 * 1000 / WHISPER_SAMPLE_RATE as u64;
+    let audio_data = match trim_silence(&audio_data, WHISPER_SAMPLE_RATE, properties.trim) {
+        None => {
+            info!(
+                "[STT] no speech in {} ms of audio, skipping model",
+                captured_ms
+            );
+            return Ok(None);
         }
+        Some(x) => x,
+    };
+    let trimmed_ms = audio_data.len() as u64 * 1000 / WHISPER_SAMPLE_RATE as u64;
+    info!(
+        "[STT] trimmed {} ms of silence ({} ms -> {} ms)",
+        captured_ms - trimmed_ms,
+        captured_ms,
+        trimmed_ms
+    );
 
-        let params = create_model_params(properties.initial_prompt);
+    let transcript = properties
+        .engine
+        .transcribe(audio_data, properties.whisper)?;
+    for segment in &transcript.segments {
+        debug!(
+            "[STT] [{} - {}]: '{}' with confidence {:.2}",
+            segment.start, segment.end, segment.text, segment.confidence
+        );
+    }
+    return Ok(Some(transcript));
+}
+
+// -----------------------------------------------------------------------------
+
+/// [SttEngine] backed by the model given to [load]
+pub struct WhisperEngine;
+
+impl SttEngine for WhisperEngine {
+    fn transcribe(&self, audio: &[f32], whisper: &Whisper) -> Result<Transcript, anyhow::Error> {
+        let mut params = create_model_params(whisper);
+        let model = current_model().ok_or_else(|| anyhow!("no model is loaded"))?;
+        let ctx = model.ctx();
+        // lives until the end of this function, which is longer than `params` is used
+        let vocabulary = VocabularyTrie::new(
+            ctx,
+            &whisper.vocabulary,
+            &whisper.conjunctions,
+            whisper.closed_vocabulary,
+            whisper.vocabulary_bias,
+        );
+        if let Some(trie) = &vocabulary {
+            trie.apply(&mut params);
+        }
 
         // get a model from the pool
-        let mut state = get_new_model().expect("failed to get model from pool");
+        let mut state = model.take_state();
 
         // run the model
-        let res = state.full(params, &audio_data);
+        let res = state.full(params, audio);
 
         // check if the model failed
         if let Err(e) = res {
             error!("model failed: {:?}", e);
-            return Err(e);
+            return Err(e.into());
         }
 
+        // tokens from end of text onwards are special (timestamps, language, ...)
+        let token_eot = ctx.token_eot();
+
         // get the result
-        let num_segments: i32 = state.full_n_segments()?;
-        // average english word length is 5.1 characters which we round up to 6
-        let mut segments = String::with_capacity(6 * num_segments as usize);
-        for i in 0..num_segments {
-            match (state.full_get_segment_text(i), properties.verbose) {
-                (Ok(s), false) => {
-                    segments.push_str(&s);
-                    if i < num_segments - 1 {
-                        segments.push('\n');
-                    }
-                }
-                (Ok(s), true) => {
-                    // also add the start and end time
-                    let start = state.full_get_segment_t0(i)?;
-                    let end = state.full_get_segment_t1(i)?;
-                    writeln!(segments, "[{} - {}]: {}", start, end, s)
-                        .expect("failed to write segment");
-                }
-                (Err(e), _) => {
+        let mut segments = Vec::with_capacity(state.full_n_segments().max(0) as usize);
+        for segment in state.as_iter() {
+            let text = match segment.to_str() {
+                Ok(s) => s.to_string(),
+                Err(e) => {
                     error!("failed to get segment text: {:?}", e);
-                    return Err(e);
+                    return Err(e.into());
                 }
             };
+
+            let mut tokens = Vec::new();
+            for token in (0..segment.n_tokens()).filter_map(|x| segment.get_token(x)) {
+                if token.token_id() >= token_eot {
+                    continue;
+                }
+                tokens.push(Token {
+                    // a token can end in the middle of a multi-byte character
+                    text: token.to_str_lossy()?.to_string(),
+                    probability: token.token_probability(),
+                });
+            }
+            let confidence = if tokens.is_empty() {
+                0.0
+            } else {
+                tokens.iter().map(|x| x.probability).sum::<f32>() / tokens.len() as f32
+            };
+
+            segments.push(Segment {
+                text,
+                start: segment.start_timestamp(),
+                end: segment.end_timestamp(),
+                confidence,
+                no_speech_probability: Some(segment.no_speech_probability()),
+                tokens,
+            });
         }
 
-        return Ok(segments);
-    }
+        let language = if whisper.language == AUTO_LANGUAGE {
+            let detected = get_lang_str(state.full_lang_id_from_state());
+            info!("[STT] detected language: {}", detected.unwrap_or("unknown"));
+            detected
+        } else {
+            Some(whisper.language.as_str())
+        };
 
-    fn update_last_access(&self) {
-        let mut last_access = self.last_access.lock();
-        *last_access = Instant::now();
+        return Ok(Transcript {
+            segments,
+            language: language.map(str::to_string),
+        });
     }
-
-    // pub fn get_last_access(&self) -> Instant {
-    //     *self.last_access.lock()
-    // }
 }
@@ -0,0 +1,210 @@
+/// Partial transcription while the record key is held
+///
+/// Every `interval_ms` the last `window_ms` of the utterance is transcribed on a worker thread,
+/// so that a command can fire before the key is released. Only one partial runs at a time,
+/// when whisper is slower than the interval then partials simply happen less often.
+use crate::audio::VoxStream;
+use crate::profiles::{Command, Config, Profile, Whisper};
+use crate::speech_to_text::{normalize_prompt, transcribe, StreamFinishProperties};
+use crate::stt_engine::Transcript;
+use crate::trim::TrimSettings;
+use log::{debug, error, info};
+use serde::Deserialize;
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StreamingSettings {
+    /// transcribe while the record key is held
+    pub enabled: bool,
+    /// how often (in milliseconds) a partial transcription is started
+    pub interval_ms: u64,
+    /// only the last this many milliseconds of the utterance are transcribed
+    pub window_ms: u64,
+    /// execute a command as soon as a partial transcript matches it
+    pub fire_early: bool,
+    /// partial transcripts need at least this confidence (0.0 to 1.0) to fire early
+    pub early_confidence: f32,
+}
+
+impl Default for StreamingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_ms: 500,
+            window_ms: 3000,
+            fire_early: false,
+            early_confidence: 0.8,
+        }
+    }
+}
+
+impl StreamingSettings {
+    /// Checks that the settings make sense, returns every problem found
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.interval_ms == 0 {
+            problems.push("streaming.interval_ms must be above 0".to_string());
+        }
+        if self.window_ms == 0 {
+            problems.push("streaming.window_ms must be above 0".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.early_confidence) {
+            problems.push("streaming.early_confidence must be between 0.0 and 1.0".to_string());
+        }
+        return problems;
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+pub struct Partial {
+    pub transcript: Transcript,
+    /// where the transcribed window ended, see [VoxStream::discard_audio]
+    pub end: usize,
+}
+
+/// Runs partial transcriptions for one press of the record key
+pub struct PartialTranscriber {
+    settings: StreamingSettings,
+    whisper: Arc<Whisper>,
+    trim: Arc<TrimSettings>,
+    worker: Option<JoinHandle<Option<Partial>>>,
+    last_started: Instant,
+}
+
+impl PartialTranscriber {
+    pub fn new(profile: &Profile) -> Self {
+        return Self {
+            settings: profile.streaming.clone(),
+            whisper: Arc::new(profile.whisper.clone()),
+            trim: Arc::new(profile.trim.clone()),
+            worker: None,
+            last_started: Instant::now(),
+        };
+    }
+
+    /// Call regularly while the key is held, starts the next partial when it is time\
+    /// returns a partial once it finished, `None` while it is running or had no speech
+    pub fn poll(&mut self, stream: &VoxStream) -> Option<Partial> {
+        if let Some(worker) = &self.worker {
+            if !worker.is_finished() {
+                return None;
+            }
+            let partial = self.worker.take()?.join().ok().flatten()?;
+            info!(
+                "[PARTIAL] '{}' with confidence {:.2}",
+                partial.transcript.text(),
+                partial.transcript.confidence()
+            );
+            return Some(partial);
+        }
+
+        if self.last_started.elapsed() < Duration::from_millis(self.settings.interval_ms) {
+            return None;
+        }
+        self.last_started = Instant::now();
+
+        let (audio, end) = stream.peek_audio(self.settings.window_ms);
+        let engine = stream.engine.clone();
+        let channels = stream.channels;
+        let downmix = stream.downmix;
+        let sample_rate = stream.sample_rate;
+        let resample_quality = stream.resample_quality;
+        let whisper = self.whisper.clone();
+        let trim = self.trim.clone();
+        self.worker = Some(thread::spawn(move || {
+            let properties = StreamFinishProperties {
+                engine,
+                whisper: &whisper,
+                channels,
+                downmix,
+                sample_rate,
+                resample_quality,
+                trim: &trim,
+            };
+            return match transcribe(audio, properties) {
+                Ok(Some(transcript)) => Some(Partial { transcript, end }),
+                Ok(None) => {
+                    debug!("[PARTIAL] no speech yet");
+                    None
+                }
+                Err(e) => {
+                    error!("[PARTIAL] transcription failed: {:?}", e);
+                    None
+                }
+            };
+        }));
+        return None;
+    }
+
+    /// The command `partial` should fire early, which it only does when `fire_early` is on,
+    /// it is confident enough and no other command name starts with it
+    pub fn early_command<'a>(&self, config: &'a Config, partial: &Partial) -> Option<&'a Command> {
+        if !self.settings.fire_early {
+            return None;
+        }
+        let transcript = &partial.transcript;
+        if transcript.confidence() < self.settings.early_confidence
+            || transcript.veto(&config.profile.whisper).is_some()
+        {
+            return None;
+        }
+        return config.get_unambiguous_command(&normalize_prompt(&transcript.text()));
+    }
+
+    /// waits for a running partial so that it does not compete with the final transcription
+    pub fn finish(self) {
+        if let Some(worker) = self.worker {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(StreamingSettings::default().validate().is_empty());
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let settings = StreamingSettings {
+            interval_ms: 0,
+            window_ms: 0,
+            early_confidence: 1.5,
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.validate(),
+            [
+                "streaming.interval_ms must be above 0",
+                "streaming.window_ms must be above 0",
+                "streaming.early_confidence must be between 0.0 and 1.0",
+            ]
+        );
+    }
+
+    #[test]
+    fn confidence_bounds_are_valid() {
+        for early_confidence in [0.0, 1.0] {
+            let settings = StreamingSettings {
+                early_confidence,
+                ..Default::default()
+            };
+            assert!(settings.validate().is_empty(), "{}", early_confidence);
+        }
+        let settings = StreamingSettings {
+            early_confidence: -0.1,
+            ..Default::default()
+        };
+        assert_eq!(settings.validate().len(), 1);
+    }
+}
@@ -0,0 +1,350 @@
+/// Backends that turn 16 kHz mono audio into text
+///
+/// Everything after capturing audio only talks to [SttEngine], so the pipeline can run
+/// without a ggml model by swapping in the [ScriptedEngine].
+use crate::profiles::{Profile, Whisper};
+use crate::settings::CommandArguments;
+use crate::speech_to_text::{self, WhisperEngine};
+use anyhow::bail;
+use log::{info, warn};
+use parking_lot::Mutex;
+use std::{collections::VecDeque, sync::Arc};
+
+pub trait SttEngine: Send + Sync {
+    /// `audio` is 16 kHz mono with silence already trimmed
+    fn transcribe(&self, audio: &[f32], whisper: &Whisper) -> Result<Transcript, anyhow::Error>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Token {
+    pub text: String,
+    /// 0.0 to 1.0
+    pub probability: f32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Segment {
+    pub text: String,
+    /// start time in centiseconds
+    pub start: i64,
+    /// end time in centiseconds
+    pub end: i64,
+    /// mean probability of the text tokens, 0.0 to 1.0
+    pub confidence: f32,
+    /// probability that the segment is not speech at all, 0.0 to 1.0\
+    /// `None` when the engine can not tell
+    pub no_speech_probability: Option<f32>,
+    /// text tokens only, special tokens (timestamps, end of text, ...) are left out
+    pub tokens: Vec<Token>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    pub segments: Vec<Segment>,
+    /// language code of the speech, detected when `whisper.language` is "auto"\
+    /// `None` when the engine can not tell
+    pub language: Option<String>,
+}
+
+impl Transcript {
+    /// segment texts joined by newlines
+    pub fn text(&self) -> String {
+        return self
+            .segments
+            .iter()
+            .map(|x| x.text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+
+    /// confidence of the least confident segment, 0.0 when there are no segments
+    pub fn confidence(&self) -> f32 {
+        return self
+            .segments
+            .iter()
+            .map(|x| x.confidence)
+            .reduce(f32::min)
+            .unwrap_or(0.0);
+    }
+
+    /// no speech probability of the segment least likely to be speech,
+    /// `None` when the engine can not tell for any segment
+    pub fn no_speech_probability(&self) -> Option<f32> {
+        return self
+            .segments
+            .iter()
+            .filter_map(|x| x.no_speech_probability)
+            .reduce(f32::max);
+    }
+
+    /// `None` when this transcript is allowed to trigger a command,
+    /// otherwise the reason it is not
+    pub fn veto(&self, whisper: &Whisper) -> Option<String> {
+        let confidence = self.confidence();
+        if confidence < whisper.min_confidence {
+            return Some(format!(
+                "confidence {:.2} is below {:.2}",
+                confidence, whisper.min_confidence
+            ));
+        }
+
+        return match self.no_speech_probability() {
+            Some(x) if x > whisper.max_no_speech => Some(format!(
+                "no speech probability {:.2} is above {:.2}",
+                x, whisper.max_no_speech
+            )),
+            _ => None,
+        };
+    }
+}
+
+/// Loads whisper with the model `profile` asks for, or the [ScriptedEngine] when
+/// `--scripted-stt` is given
+pub fn new_engine(args: &CommandArguments, profile: &Profile) -> Arc<dyn SttEngine> {
+    if let Some(path) = &args.scripted_stt {
+        info!("using scripted transcripts from {}", path);
+        let engine = ScriptedEngine::from_file(path).expect("readable script file required");
+        return Arc::new(engine);
+    }
+
+    // a profile can ask for its own model, loading `--model-path` first would load twice
+    let model_path = profile.model_path(args);
+    speech_to_text::load(&model_path, args.state_pool_size).expect("failed to load model");
+    return Arc::new(WhisperEngine);
+}
+
+// -----------------------------------------------------------------------------
+
+/// Returns preset transcripts in order, one per utterance, regardless of the audio
+pub struct ScriptedEngine {
+    /// text and confidence
+    transcripts: Mutex<VecDeque<(String, f32)>>,
+}
+
+impl ScriptedEngine {
+    /// one transcript per line, empty lines are skipped\
+    /// a confidence can be given after a `|`, for example `orbital laser|0.4`
+    pub fn from_file(path: &str) -> Result<Self, anyhow::Error> {
+        let content = std::fs::read_to_string(path)?;
+        let mut transcripts = VecDeque::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (text, confidence) = match line.rsplit_once('|') {
+                None => (line, 1.0),
+                Some((text, confidence)) => match confidence.trim().parse::<f32>() {
+                    Ok(x) => (text.trim(), x),
+                    Err(e) => bail!("invalid confidence on line {}: {}", number + 1, e),
+                },
+            };
+            transcripts.push_back((text.to_string(), confidence));
+        }
+
+        return Ok(Self {
+            transcripts: Mutex::new(transcripts),
+        });
+    }
+}
+
+impl SttEngine for ScriptedEngine {
+    fn transcribe(&self, audio: &[f32], _whisper: &Whisper) -> Result<Transcript, anyhow::Error> {
+        let (text, confidence) = match self.transcripts.lock().pop_front() {
+            Some(x) => x,
+            None => {
+                warn!("[STT] scripted transcripts ran out, returning nothing");
+                (String::new(), 0.0)
+            }
+        };
+
+        // timestamps are in centiseconds
+        let end = audio.len() as i64 * 100 / speech_to_text::WHISPER_SAMPLE_RATE as i64;
+        return Ok(Transcript {
+            segments: vec![Segment {
+                text,
+                start: 0,
+                end,
+                confidence,
+                no_speech_probability: None,
+                tokens: Vec::new(),
+            }],
+            language: None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downmix::Downmix;
+    use crate::profiles::Config;
+    use crate::resample::ResampleQuality;
+    use crate::speech_to_text::{transcribe, StreamFinishProperties};
+    use clap::Parser;
+    use std::{f32::consts::PI, path::PathBuf};
+
+    const PROFILE: &str = r#"
+record_keybind = "numpad1"
+
+[whisper]
+initial_prompt = ""
+min_confidence = 0.6
+
+[[commands]]
+name = "resupply"
+action = "swsa"
+
+[[commands]]
+name = "reinforce"
+action = "wsdaw"
+aliases = ["reinforcements"]
+
+[[commands]]
+name = "orbital laser"
+action = "dswds"
+"#;
+
+    /// captured like a stereo 48 kHz microphone would
+    const SAMPLE_RATE: u32 = 48000;
+    const CHANNELS: u16 = 2;
+
+    /// directory in the temp directory that no other test uses, removed on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(test: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("vox-strike-{}-{}", std::process::id(), test));
+            std::fs::create_dir_all(&path).expect("writable temp directory required");
+            return Self(path);
+        }
+
+        /// writes `contents` to `name` in this directory, returns its path
+        fn file(&self, name: &str, contents: &str) -> String {
+            let path = self.0.join(name);
+            std::fs::write(&path, contents).expect("writable temp directory required");
+            return path.to_string_lossy().to_string();
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    struct Setup {
+        config: Config,
+        engine: Arc<dyn SttEngine>,
+        /// kept until the test ends
+        _directory: TempDir,
+    }
+
+    fn setup(test: &str, script: &str) -> Setup {
+        let directory = TempDir::new(test);
+        let profile_path = directory.file("profile.toml", PROFILE);
+        let script_path = directory.file("script.txt", script);
+        let args = CommandArguments::parse_from([
+            "vox-strike",
+            "--profile-path",
+            &profile_path,
+            "--scripted-stt",
+            &script_path,
+        ]);
+        let profile = Profile::new(&args).expect("valid profile required");
+        return Setup {
+            engine: new_engine(&args, &profile),
+            config: Config::from_profile(profile).expect("valid profile required"),
+            _directory: directory,
+        };
+    }
+
+    /// one second of interleaved audio, a tone or silence
+    fn capture(loud: bool) -> Vec<f32> {
+        let amplitude = if loud { 0.3 } else { 0.0 };
+        return (0..SAMPLE_RATE as usize)
+            .flat_map(|x| {
+                let sample = amplitude * (2.0 * PI * 220.0 * x as f32 / SAMPLE_RATE as f32).sin();
+                std::iter::repeat_n(sample, CHANNELS as usize)
+            })
+            .collect();
+    }
+
+    fn run(setup: &Setup, loud: bool) -> Option<Transcript> {
+        let properties = StreamFinishProperties {
+            engine: setup.engine.clone(),
+            whisper: &setup.config.profile.whisper,
+            channels: CHANNELS,
+            downmix: Downmix::Average,
+            sample_rate: SAMPLE_RATE,
+            resample_quality: ResampleQuality::Linear,
+            trim: &setup.config.profile.trim,
+        };
+        return transcribe(capture(loud), properties).expect("scripted transcription to work");
+    }
+
+    /// names of the commands `transcript` triggers, "?" for parts that match none
+    fn command_names(setup: &Setup, transcript: &Transcript) -> Vec<String> {
+        return setup
+            .config
+            .match_commands(&transcript.text())
+            .iter()
+            .map(|(_, command)| command.map_or("?".to_string(), |x| x.name.clone()))
+            .collect();
+    }
+
+    #[test]
+    fn scripted_transcripts_trigger_commands() {
+        let script = "Resupply.\nReinforcements and resupply!\n\norbital lazer|0.9\nmortar|0.9\n";
+        let setup = setup("trigger", script);
+
+        let expected: [&[&str]; 4] = [
+            &["resupply"],
+            &["reinforce", "resupply"],
+            &["orbital laser"],
+            &["?"],
+        ];
+        for commands in expected {
+            let transcript = run(&setup, true).expect("speech in the capture");
+            assert_eq!(transcript.veto(&setup.config.profile.whisper), None);
+            assert_eq!(command_names(&setup, &transcript), commands);
+        }
+        // the script ran out
+        let transcript = run(&setup, true).expect("speech in the capture");
+        assert_eq!(transcript.text(), "");
+    }
+
+    #[test]
+    fn low_confidence_is_vetoed() {
+        let setup = setup("veto", "resupply|0.3\nresupply|0.6\n");
+        let whisper = &setup.config.profile.whisper;
+
+        let transcript = run(&setup, true).expect("speech in the capture");
+        let reason = transcript.veto(whisper).expect("a veto");
+        assert!(reason.contains("confidence 0.30"), "{}", reason);
+        // the veto is about confidence, the text itself would match
+        assert_eq!(command_names(&setup, &transcript), ["resupply"]);
+
+        let transcript = run(&setup, true).expect("speech in the capture");
+        assert_eq!(transcript.veto(whisper), None);
+    }
+
+    #[test]
+    fn silence_never_reaches_the_engine() {
+        let setup = setup("silence", "resupply\nreinforce\n");
+
+        assert!(run(&setup, false).is_none());
+        // the first line was not used up by the silent capture
+        let transcript = run(&setup, true).expect("speech in the capture");
+        assert_eq!(transcript.text(), "resupply");
+    }
+
+    #[test]
+    fn invalid_confidence_is_an_error() {
+        let directory = TempDir::new("invalid");
+        let path = directory.file("script.txt", "resupply\nreinforce|high\n");
+        let error = ScriptedEngine::from_file(&path).err().expect("an error");
+        assert!(error.to_string().contains("line 2"), "{}", error);
+    }
+}
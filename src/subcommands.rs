@@ -0,0 +1,231 @@
+/// Handlers for `settings::SubCommand`
+use crate::{
+    audio::{list_input_devices, AudioSource, WavSource},
+    model::{file_name, read_manifest, sha256_file, synthetic_speech, ModelInfo},
+    phonetic::PhoneticKeys,
+    profiles::{Config, Profile},
+    settings::{CommandArguments, ModelCommand},
+    speech_to_text::{self, normalize_prompt, WhisperEngine, WHISPER_SAMPLE_RATE},
+    stt_engine::{self, SttEngine},
+};
+use log::{debug, info};
+use std::time::{Duration, Instant};
+
+/// length of the synthetic audio used by `model bench`
+const BENCH_SECONDS: f32 = 3.0;
+
+/// Runs a WAV file through the same pipeline as the record keybind and prints the result
+pub fn transcribe(args: &CommandArguments, file: &str) {
+    let profile = Profile::new(args).expect("valid profile required");
+    let engine = stt_engine::new_engine(args, &profile);
+    let config = Config::from_profile(profile).expect("valid profile required");
+    let source = WavSource::new(file, args, engine).expect("readable WAV file required");
+
+    let stream_result = match source.new_stream(true).finish_stream(&config.profile) {
+        None => {
+            println!("no speech found in '{}'", file);
+            return;
+        }
+        Some(x) => x,
+    };
+    info!("[TRANSCRIBE] stream result: {}", stream_result.text());
+    if let Some(language) = &stream_result.language {
+        println!("language: {}", language);
+    }
+    println!("confidence: {:.2}", stream_result.confidence());
+    // per token so that `min_confidence` can be tuned
+    for token in stream_result.segments.iter().flat_map(|x| &x.tokens) {
+        println!("  '{}': {:.2}", token.text, token.probability);
+    }
+    // so that `max_no_speech` can be tuned
+    if let Some(no_speech) = stream_result.no_speech_probability() {
+        println!("no speech probability: {:.2}", no_speech);
+    }
+    if let Some(reason) = stream_result.veto(&config.profile.whisper) {
+        println!("no command would fire, {}", reason);
+        return;
+    }
+
+    for (segment, command) in config.match_commands(&stream_result.text()) {
+        let processed_result = normalize_prompt(&segment);
+        match command {
+            None => println!("no command found with '{}'", processed_result),
+            Some(c) => println!(
+                "'{}' matched command '{}' (action: {}, modifiers: {:?})",
+                processed_result, c.name, c.action, c.modifiers
+            ),
+        }
+    }
+}
+
+/// Prints every input device name that can be given to `--audio-in`
+pub fn list_devices(json: bool) {
+    let hosts = list_input_devices();
+    if json {
+        let output = serde_json::to_string_pretty(&hosts).expect("serializable device list");
+        println!("{}", output);
+        return;
+    }
+
+    for host in hosts {
+        println!("{}", host.name);
+        for device in host.input_devices {
+            let default = if device.is_default { " (default)" } else { "" };
+            println!("  \"{}\"{}", device.name, default);
+            for config in device.configs {
+                println!(
+                    "    channels: {}, sample rate: {} - {} Hz, format: {}",
+                    config.channels,
+                    config.min_sample_rate,
+                    config.max_sample_rate,
+                    config.sample_format
+                );
+            }
+        }
+    }
+}
+
+/// Runs `model info`, `model verify` or `model bench`
+pub fn model(args: &CommandArguments, command: &ModelCommand) {
+    match command {
+        ModelCommand::Info { path } => model_info(path.as_ref().unwrap_or(&args.model_path)),
+        ModelCommand::Verify { path, manifest } => {
+            model_verify(path.as_ref().unwrap_or(&args.model_path), manifest)
+        }
+        ModelCommand::Bench { path, runs } => {
+            model_bench(args, path.as_ref().unwrap_or(&args.model_path), *runs)
+        }
+    }
+}
+
+fn model_info(path: &str) {
+    let model_info = ModelInfo::read(path).expect("readable model required");
+    let language = if model_info.is_multilingual() {
+        "multilingual"
+    } else {
+        "english only"
+    };
+
+    println!("{}", path);
+    println!(
+        "  size: {:.1} MiB",
+        model_info.file_size as f64 / (1024.0 * 1024.0)
+    );
+    println!(
+        "  type: {} ({} audio layers, {} text layers)",
+        model_info.model_type(),
+        model_info.n_audio_layer,
+        model_info.n_text_layer
+    );
+    println!("  languages: {}", language);
+    println!(
+        "  quantization: {} (ftype {})",
+        model_info.quantization(),
+        model_info.ftype
+    );
+    println!(
+        "  vocabulary: {}, mels: {}, audio context: {}, text context: {}",
+        model_info.n_vocab, model_info.n_mels, model_info.n_audio_ctx, model_info.n_text_ctx
+    );
+    println!(
+        "  audio state: {}, audio heads: {}, text state: {}, text heads: {}",
+        model_info.n_audio_state,
+        model_info.n_audio_head,
+        model_info.n_text_state,
+        model_info.n_text_head
+    );
+}
+
+/// exits with 1 when the hash does not match or the model is not in the manifest
+fn model_verify(path: &str, manifest: &str) {
+    let hashes = read_manifest(manifest).expect("readable manifest required");
+    let name = file_name(path);
+    let expected = match hashes.get(&name) {
+        None => {
+            println!("'{}' is not listed in '{}'", name, manifest);
+            std::process::exit(1);
+        }
+        Some(x) => x,
+    };
+
+    let actual = sha256_file(path).expect("readable model required");
+    if &actual != expected {
+        println!("'{}' does not match '{}'", path, manifest);
+        println!("  expected: {}", expected);
+        println!("  actual:   {}", actual);
+        std::process::exit(1);
+    }
+    println!("'{}' matches '{}' ({})", path, manifest, actual);
+}
+
+fn model_bench(args: &CommandArguments, path: &str, runs: usize) {
+    let model_info = ModelInfo::read(path).expect("readable model required");
+    let start = Instant::now();
+    speech_to_text::load(path, 1).expect("failed to load model");
+    let load_time = start.elapsed();
+
+    let config = Config::new(args).expect("valid profile required");
+    let audio = synthetic_speech(BENCH_SECONDS, WHISPER_SAMPLE_RATE);
+    let engine = WhisperEngine;
+    let mut latencies = Vec::with_capacity(runs);
+    for run in 0..runs.max(1) {
+        let start = Instant::now();
+        let transcript = engine
+            .transcribe(&audio, &config.profile.whisper)
+            .expect("transcription to work");
+        latencies.push(start.elapsed());
+        debug!("[BENCH] run {} transcribed '{}'", run, transcript.text());
+    }
+
+    let total: Duration = latencies.iter().sum();
+    let mean = total / latencies.len() as u32;
+    let min = latencies.iter().min().expect("at least one run");
+    let max = latencies.iter().max().expect("at least one run");
+    println!(
+        "{} ({} {})",
+        path,
+        model_info.model_type(),
+        model_info.quantization()
+    );
+    println!("  load and warm-up: {} ms", load_time.as_millis());
+    println!(
+        "  {} runs on {:.1} s of audio: mean {} ms, min {} ms, max {} ms",
+        latencies.len(),
+        BENCH_SECONDS,
+        mean.as_millis(),
+        min.as_millis(),
+        max.as_millis()
+    );
+    println!(
+        "  real-time factor: {:.2}",
+        mean.as_secs_f32() / BENCH_SECONDS
+    );
+}
+
+// -----------------------------------------------------------------------------
+
+/// Prints the phonetic keys of every command name, then the commands that share a key
+pub fn phonetic_keys(args: &CommandArguments) {
+    let config = Config::new(args).expect("valid profile required");
+    let commands = &config.profile.commands;
+    for name in commands.iter().flat_map(|x| x.names()) {
+        match PhoneticKeys::new(name) {
+            None => println!("'{}': no phonetic key", name),
+            Some(keys) if keys.primary == keys.alternate => {
+                println!("'{}': {}", name, keys.primary)
+            }
+            Some(keys) => println!("'{}': {} / {}", name, keys.primary, keys.alternate),
+        }
+    }
+
+    let collisions = config.phonetic.collisions();
+    if collisions.is_empty() {
+        println!("no collisions");
+        return;
+    }
+    println!("sound the same, these only match by spelling:");
+    for (key, indexes) in collisions {
+        let names: Vec<&str> = indexes.iter().map(|x| commands[*x].name.as_str()).collect();
+        println!("  {}: {}", key, names.join(", "));
+    }
+}
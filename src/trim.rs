@@ -0,0 +1,138 @@
+/// Silence trimming before inference
+///
+/// Whisper tends to hallucinate phrases like "Thank you." on silence, and every second of audio
+/// costs inference time, so silent edges are cut and utterances without speech never reach it.
+use crate::vad::rms;
+use serde::Deserialize;
+
+/// length (in milliseconds) of the frames that loudness is measured over
+const FRAME_MS: usize = 10;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TrimSettings {
+    /// frames with a lower RMS than this are silence, samples are in the range of -1.0..1.0
+    pub threshold: f32,
+    /// audio with less than this many milliseconds between the first and last loud frame
+    /// is treated as no speech, e.g. accidental taps of the record key
+    pub min_speech_ms: u32,
+    /// silence (in milliseconds) to keep around the speech, whisper likes a little context
+    pub margin_ms: u32,
+}
+
+impl Default for TrimSettings {
+    fn default() -> Self {
+        Self {
+            threshold: 0.01,
+            min_speech_ms: 150,
+            margin_ms: 150,
+        }
+    }
+}
+
+impl TrimSettings {
+    /// Checks that the settings make sense, returns every problem found
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !(0.0..=1.0).contains(&self.threshold) {
+            problems.push("trim.threshold must be between 0.0 and 1.0".to_string());
+        }
+        if self.min_speech_ms == 0 {
+            problems.push("trim.min_speech_ms must be above 0".to_string());
+        }
+        return problems;
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Cuts leading and trailing silence from mono `audio`\
+/// returns `None` when there is not enough speech to be worth transcribing
+pub fn trim_silence<'a>(
+    audio: &'a [f32],
+    sample_rate: u32,
+    settings: &TrimSettings,
+) -> Option<&'a [f32]> {
+    let frame_length = (sample_rate as usize * FRAME_MS / 1000).max(1);
+    let is_loud = |frame: &[f32]| rms(frame) >= settings.threshold;
+
+    let first = audio.chunks(frame_length).position(is_loud)?;
+    let last = audio.chunks(frame_length).rposition(is_loud)?;
+
+    let speech_ms = (last - first + 1) * FRAME_MS;
+    if speech_ms < settings.min_speech_ms as usize {
+        return None;
+    }
+
+    let margin = sample_rate as usize * settings.margin_ms as usize / 1000;
+    let start = (first * frame_length).saturating_sub(margin);
+    let end = ((last + 1) * frame_length + margin).min(audio.len());
+    return Some(&audio[start..end]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 16000;
+
+    fn samples(ms: usize) -> usize {
+        return SAMPLE_RATE as usize * ms / 1000;
+    }
+
+    /// `silence_ms` of silence, `speech_ms` of a loud tone, `silence_ms` of silence
+    fn utterance(silence_ms: usize, speech_ms: usize) -> Vec<f32> {
+        let silence = vec![0.0; samples(silence_ms)];
+        let speech: Vec<f32> = (0..samples(speech_ms))
+            .map(|x| 0.3 * (x as f32 * 0.1).sin())
+            .collect();
+        return [silence.clone(), speech, silence].concat();
+    }
+
+    #[test]
+    fn silence_is_no_speech() {
+        let settings = TrimSettings::default();
+        assert_eq!(trim_silence(&[], SAMPLE_RATE, &settings), None);
+        let silence = vec![0.001; samples(2000)];
+        assert_eq!(trim_silence(&silence, SAMPLE_RATE, &settings), None);
+    }
+
+    #[test]
+    fn short_blip_is_no_speech() {
+        let settings = TrimSettings::default();
+        let blip = utterance(500, settings.min_speech_ms as usize - 50);
+        assert_eq!(trim_silence(&blip, SAMPLE_RATE, &settings), None);
+    }
+
+    #[test]
+    fn silent_edges_are_trimmed_to_margin() {
+        let settings = TrimSettings::default();
+        let audio = utterance(1000, 500);
+
+        let trimmed = trim_silence(&audio, SAMPLE_RATE, &settings).unwrap();
+        let margin = samples(settings.margin_ms as usize);
+        assert_eq!(trimmed.len(), samples(500) + 2 * margin);
+        // the trimmed audio starts `margin` samples before the speech
+        let start = trimmed.as_ptr() as usize - audio.as_ptr() as usize;
+        assert_eq!(start / size_of::<f32>(), samples(1000) - margin);
+    }
+
+    #[test]
+    fn margin_stops_at_the_edges() {
+        let settings = TrimSettings::default();
+        let audio = utterance(50, 500);
+        let trimmed = trim_silence(&audio, SAMPLE_RATE, &settings).unwrap();
+        assert_eq!(trimmed.len(), audio.len());
+    }
+
+    #[test]
+    fn validate_rejects_nonsense() {
+        assert!(TrimSettings::default().validate().is_empty());
+        let settings = TrimSettings {
+            threshold: -0.1,
+            min_speech_ms: 0,
+            margin_ms: 0,
+        };
+        assert_eq!(settings.validate().len(), 2);
+    }
+}
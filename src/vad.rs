@@ -0,0 +1,511 @@
+/// Voice activity detection for the hands-free (open mic) mode
+///
+/// Audio is split into short frames which are classified as speech when they are loud enough
+/// and do not cross zero too often (hiss and fan noise cross zero a lot more than voiced speech).
+/// The spectral variant additionally requires the frame spectrum to not be flat like noise.
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+/// maximum amount of frequency bands used by [spectral_flatness]
+const SPECTRAL_BANDS: usize = 64;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct VadSettings {
+    /// length (in milliseconds) of the frames that are classified
+    pub frame_ms: u32,
+    /// minimum RMS of a speech frame, samples are in the range of -1.0..1.0
+    pub energy_threshold: f32,
+    /// maximum ratio of samples in a speech frame that cross zero
+    pub max_zero_crossing_rate: f32,
+    /// also check spectral flatness, costs a small DFT per frame
+    pub spectral: bool,
+    /// maximum spectral flatness of a speech frame, 0 is a pure tone and 1 is white noise
+    pub max_spectral_flatness: f32,
+    /// how long (in milliseconds) speech has to be missing before an utterance ends
+    pub hangover_ms: u32,
+    /// utterances with less speech (in milliseconds) than this are discarded
+    pub min_speech_ms: u32,
+    /// audio (in milliseconds) from before speech was detected to include in the utterance
+    pub padding_ms: u32,
+    /// utterances are cut off after this many milliseconds
+    pub max_utterance_ms: u32,
+}
+
+impl Default for VadSettings {
+    fn default() -> Self {
+        Self {
+            frame_ms: 20,
+            energy_threshold: 0.02,
+            max_zero_crossing_rate: 0.3,
+            spectral: false,
+            max_spectral_flatness: 0.5,
+            hangover_ms: 400,
+            min_speech_ms: 150,
+            padding_ms: 200,
+            max_utterance_ms: 8000,
+        }
+    }
+}
+
+impl VadSettings {
+    /// Checks that the settings make sense, returns every problem found
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.frame_ms == 0 {
+            problems.push("vad.frame_ms must be above 0".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.energy_threshold) {
+            problems.push("vad.energy_threshold must be between 0.0 and 1.0".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.max_zero_crossing_rate) {
+            problems.push("vad.max_zero_crossing_rate must be between 0.0 and 1.0".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.max_spectral_flatness) {
+            problems.push("vad.max_spectral_flatness must be between 0.0 and 1.0".to_string());
+        }
+        if self.max_utterance_ms <= self.min_speech_ms {
+            problems.push("vad.max_utterance_ms must be above vad.min_speech_ms".to_string());
+        }
+        return problems;
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// root mean square of `frame`
+pub fn rms(frame: &[f32]) -> f32 {
+    if frame.is_empty() {
+        return 0.0;
+    }
+    let sum: f32 = frame.iter().map(|x| x * x).sum();
+    return (sum / frame.len() as f32).sqrt();
+}
+
+/// ratio of neighbouring samples in `frame` that have a different sign
+pub fn zero_crossing_rate(frame: &[f32]) -> f32 {
+    if frame.len() < 2 {
+        return 0.0;
+    }
+    let crossings = frame
+        .windows(2)
+        .filter(|x| (x[0] >= 0.0) != (x[1] >= 0.0))
+        .count();
+    return crossings as f32 / (frame.len() - 1) as f32;
+}
+
+/// power of the bins 0..=n/2 of the frame, zero padded to a power of two\
+/// iterative radix-2 FFT, the twiddle factors are computed directly so they do not drift
+fn power_spectrum(frame: &[f32]) -> Vec<f32> {
+    let n = frame.len().next_power_of_two();
+    let mut re = frame.to_vec();
+    re.resize(n, 0.0);
+    let mut im = vec![0.0; n];
+
+    let bits = n.trailing_zeros();
+    for i in 0..n {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if i < j {
+            re.swap(i, j);
+        }
+    }
+
+    let mut length = 2;
+    while length <= n {
+        let half = length / 2;
+        for k in 0..half {
+            let (sin, cos) = (-2.0 * PI * k as f32 / length as f32).sin_cos();
+            for start in (0..n).step_by(length) {
+                let (a, b) = (start + k, start + k + half);
+                let b_re = re[b] * cos - im[b] * sin;
+                let b_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - b_re;
+                im[b] = im[a] - b_im;
+                re[a] += b_re;
+                im[a] += b_im;
+            }
+        }
+        length *= 2;
+    }
+
+    return (0..=n / 2).map(|k| re[k] * re[k] + im[k] * im[k]).collect();
+}
+
+/// geometric mean divided by arithmetic mean of the power spectrum\
+/// neighbouring bins are summed into at most [SPECTRAL_BANDS] bands, single bins of noise
+/// vary so much that many noise frames would otherwise look like a tone
+pub fn spectral_flatness(frame: &[f32]) -> f32 {
+    let n = frame.len();
+    if n < 4 {
+        return 1.0;
+    }
+
+    // bin 0 (DC) is skipped, microphones often have an offset
+    let spectrum = power_spectrum(frame);
+    let bins = spectrum.len() - 1;
+    let band_count = bins.min(SPECTRAL_BANDS);
+    let mut bands = vec![0.0; band_count];
+    for (k, power) in spectrum.iter().enumerate().skip(1) {
+        bands[(k - 1) * band_count / bins] += power;
+    }
+
+    // tiny offset keeps silent bands from sending the logarithm to -inf
+    let log_sum: f32 = bands.iter().map(|x| (x + 1e-10).ln()).sum();
+    let sum: f32 = bands.iter().map(|x| x + 1e-10).sum();
+    let geometric_mean = (log_sum / band_count as f32).exp();
+    let arithmetic_mean = sum / band_count as f32;
+    return geometric_mean / arithmetic_mean;
+}
+
+// -----------------------------------------------------------------------------
+
+/// Splits a continuous mono stream into utterances
+pub struct VoiceActivityDetector {
+    settings: VadSettings,
+    frame_length: usize,
+    /// samples that do not fill a whole frame yet
+    pending: Vec<f32>,
+    /// frames from before speech started, kept for padding
+    padding: VecDeque<Vec<f32>>,
+    padding_frames: usize,
+    utterance: Vec<f32>,
+    in_speech: bool,
+    speech_frames: usize,
+    silent_frames: usize,
+}
+
+impl VoiceActivityDetector {
+    pub fn new(settings: &VadSettings, sample_rate: u32) -> Self {
+        let frame_length = (sample_rate as usize * settings.frame_ms as usize / 1000).max(1);
+
+        return Self {
+            settings: settings.clone(),
+            frame_length,
+            pending: Vec::with_capacity(frame_length),
+            padding: VecDeque::new(),
+            padding_frames: settings.padding_ms.div_ceil(settings.frame_ms) as usize,
+            utterance: Vec::new(),
+            in_speech: false,
+            speech_frames: 0,
+            silent_frames: 0,
+        };
+    }
+
+    pub fn is_speech(&self, frame: &[f32]) -> bool {
+        if rms(frame) < self.settings.energy_threshold {
+            return false;
+        }
+        if zero_crossing_rate(frame) > self.settings.max_zero_crossing_rate {
+            return false;
+        }
+        if self.settings.spectral && spectral_flatness(frame) > self.settings.max_spectral_flatness
+        {
+            return false;
+        }
+        return true;
+    }
+
+    /// Feeds mono audio, returns the utterances that ended within it
+    pub fn process(&mut self, audio: &[f32]) -> Vec<Vec<f32>> {
+        let mut finished = Vec::new();
+
+        for sample in audio {
+            self.pending.push(*sample);
+            if self.pending.len() < self.frame_length {
+                continue;
+            }
+
+            let frame = std::mem::replace(&mut self.pending, Vec::with_capacity(self.frame_length));
+            if let Some(utterance) = self.process_frame(frame) {
+                finished.push(utterance);
+            }
+        }
+
+        return finished;
+    }
+
+    fn process_frame(&mut self, frame: Vec<f32>) -> Option<Vec<f32>> {
+        let is_speech = self.is_speech(&frame);
+
+        if !self.in_speech {
+            if !is_speech {
+                self.padding.push_back(frame);
+                while self.padding.len() > self.padding_frames {
+                    self.padding.pop_front();
+                }
+                return None;
+            }
+
+            self.in_speech = true;
+            self.speech_frames = 0;
+            self.silent_frames = 0;
+            self.utterance.clear();
+            for padding_frame in self.padding.drain(..) {
+                self.utterance.extend_from_slice(&padding_frame);
+            }
+        }
+
+        self.utterance.extend_from_slice(&frame);
+        if is_speech {
+            self.speech_frames += 1;
+            self.silent_frames = 0;
+        } else {
+            self.silent_frames += 1;
+        }
+
+        let frame_ms = self.settings.frame_ms as usize;
+        let utterance_ms = self.utterance.len() / self.frame_length * frame_ms;
+        if self.silent_frames * frame_ms >= self.settings.hangover_ms as usize
+            || utterance_ms >= self.settings.max_utterance_ms as usize
+        {
+            return self.end_utterance();
+        }
+
+        return None;
+    }
+
+    fn end_utterance(&mut self) -> Option<Vec<f32>> {
+        self.in_speech = false;
+        self.silent_frames = 0;
+        let utterance = std::mem::take(&mut self.utterance);
+
+        let speech_ms = self.speech_frames * self.settings.frame_ms as usize;
+        if speech_ms < self.settings.min_speech_ms as usize {
+            return None;
+        }
+        return Some(utterance);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{AudioSource, WavSource};
+    use crate::profiles::Whisper;
+    use crate::settings::CommandArguments;
+    use crate::stt_engine::{SttEngine, Transcript};
+    use clap::Parser;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const SAMPLE_RATE: u32 = 16000;
+
+    /// the fixtures only go through the audio side, nothing is transcribed
+    struct NoEngine;
+
+    impl SttEngine for NoEngine {
+        fn transcribe(
+            &self,
+            _audio: &[f32],
+            _whisper: &Whisper,
+        ) -> Result<Transcript, anyhow::Error> {
+            return Ok(Transcript::default());
+        }
+    }
+
+    /// writes `audio` as a 16 bit WAV fixture and reads it back like `--input-wav` does
+    fn through_wav(audio: &[f32]) -> Vec<f32> {
+        static FIXTURES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "vox-strike-vad-{}-{}.wav",
+            std::process::id(),
+            FIXTURES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let path = path.to_str().expect("utf-8 temp path required");
+
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).expect("writable fixture required");
+        for sample in audio {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            writer
+                .write_sample(sample)
+                .expect("writable fixture required");
+        }
+        writer.finalize().expect("writable fixture required");
+
+        let args = CommandArguments::parse_from(["vox-strike"]);
+        let source = WavSource::new(path, &args, Arc::new(NoEngine));
+        std::fs::remove_file(path).expect("removable fixture required");
+        let source = source.expect("readable fixture required");
+        return source.new_stream(true).take_mono_audio();
+    }
+
+    fn samples(ms: u32) -> usize {
+        return (SAMPLE_RATE * ms / 1000) as usize;
+    }
+
+    /// uniform white noise from a fixed seed, RMS is `amplitude / sqrt(3)`
+    fn noise(ms: u32, amplitude: f32, seed: &mut u32) -> Vec<f32> {
+        return (0..samples(ms))
+            .map(|_| {
+                *seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (*seed >> 8) as f32 / (1 << 23) as f32 * amplitude - amplitude
+            })
+            .collect();
+    }
+
+    fn tone(ms: u32) -> Vec<f32> {
+        return (0..samples(ms))
+            .map(|x| 0.3 * (2.0 * PI * 330.0 * x as f32 / SAMPLE_RATE as f32).sin())
+            .collect();
+    }
+
+    /// noise too quiet to be speech
+    fn quiet(ms: u32, seed: &mut u32) -> Vec<f32> {
+        return noise(ms, 0.01, seed);
+    }
+
+    fn join(parts: &[Vec<f32>]) -> Vec<f32> {
+        return parts.concat();
+    }
+
+    /// utterance lengths in milliseconds
+    fn detect(settings: &VadSettings, audio: &[f32]) -> Vec<u32> {
+        let audio = through_wav(audio);
+        let mut detector = VoiceActivityDetector::new(settings, SAMPLE_RATE);
+        // fed in uneven chunks like the audio callback does
+        let mut utterances = Vec::new();
+        for chunk in audio.chunks(997) {
+            utterances.extend(detector.process(chunk));
+        }
+        return utterances
+            .iter()
+            .map(|x| (x.len() * 1000 / SAMPLE_RATE as usize) as u32)
+            .collect();
+    }
+
+    #[test]
+    fn power_spectrum_matches_plain_dft() {
+        let mut seed = 7;
+        // 20 ms at 16 kHz, zero padded to 512
+        let frame = join(&[tone(10), noise(10, 0.2, &mut seed)]);
+        let spectrum = power_spectrum(&frame);
+        assert_eq!(spectrum.len(), 257);
+
+        for (k, power) in spectrum.iter().enumerate() {
+            let (mut re, mut im) = (0.0, 0.0);
+            for (i, sample) in frame.iter().enumerate() {
+                let angle = -2.0 * PI * (k * i) as f32 / 512.0;
+                re += sample * angle.cos();
+                im += sample * angle.sin();
+            }
+            let expected = re * re + im * im;
+            assert!(
+                (power - expected).abs() <= 1e-3 * expected.max(1.0),
+                "bin {}",
+                k
+            );
+        }
+    }
+
+    #[test]
+    fn one_utterance_per_tone() {
+        let settings = VadSettings::default();
+        let mut seed = 1;
+        let audio = join(&[quiet(1000, &mut seed), tone(1000), quiet(1000, &mut seed)]);
+
+        // padding before the tone and the hangover after it are part of the utterance
+        let expected = settings.padding_ms + 1000 + settings.hangover_ms;
+        assert_eq!(detect(&settings, &audio), vec![expected]);
+    }
+
+    #[test]
+    fn hangover_ends_utterance() {
+        let settings = VadSettings::default();
+        let mut seed = 1;
+
+        let short_pause = join(&[
+            quiet(500, &mut seed),
+            tone(500),
+            quiet(settings.hangover_ms - 100, &mut seed),
+            tone(500),
+            quiet(1000, &mut seed),
+        ]);
+        assert_eq!(detect(&settings, &short_pause).len(), 1);
+
+        let long_pause = join(&[
+            quiet(500, &mut seed),
+            tone(500),
+            quiet(settings.hangover_ms + 100, &mut seed),
+            tone(500),
+            quiet(1000, &mut seed),
+        ]);
+        assert_eq!(detect(&settings, &long_pause).len(), 2);
+
+        // nothing is emitted while the hangover has not passed yet
+        let unfinished = join(&[
+            quiet(500, &mut seed),
+            tone(500),
+            quiet(settings.hangover_ms - 100, &mut seed),
+        ]);
+        assert!(detect(&settings, &unfinished).is_empty());
+    }
+
+    #[test]
+    fn short_blips_are_dropped() {
+        let settings = VadSettings::default();
+        let mut seed = 1;
+
+        let blip = join(&[
+            quiet(500, &mut seed),
+            tone(settings.min_speech_ms - 60),
+            quiet(1000, &mut seed),
+        ]);
+        assert!(detect(&settings, &blip).is_empty());
+
+        let word = join(&[
+            quiet(500, &mut seed),
+            tone(settings.min_speech_ms + 60),
+            quiet(1000, &mut seed),
+        ]);
+        assert_eq!(detect(&settings, &word).len(), 1);
+    }
+
+    #[test]
+    fn long_speech_is_cut_off() {
+        let settings = VadSettings {
+            max_utterance_ms: 1000,
+            ..Default::default()
+        };
+        let mut seed = 1;
+        let audio = join(&[quiet(500, &mut seed), tone(3000), quiet(1000, &mut seed)]);
+
+        let utterances = detect(&settings, &audio);
+        assert!(utterances.len() >= 3, "{:?}", utterances);
+        for length in &utterances {
+            assert!(*length <= settings.max_utterance_ms, "{:?}", utterances);
+        }
+        // nothing of the tone is lost between the pieces
+        let total: u32 = utterances.iter().sum();
+        assert!(total >= 3000, "{:?}", utterances);
+    }
+
+    #[test]
+    fn spectral_rejects_loud_noise() {
+        // zero crossings alone already reject white noise, only flatness is tested here
+        let energy_only = VadSettings {
+            max_zero_crossing_rate: 1.0,
+            ..Default::default()
+        };
+        let spectral = VadSettings {
+            spectral: true,
+            ..energy_only.clone()
+        };
+        let mut seed = 1;
+        let loud = noise(3000, 0.5, &mut seed);
+        assert!(rms(&loud) > 10.0 * spectral.energy_threshold);
+        let audio = join(&[quiet(1000, &mut seed), loud, quiet(1000, &mut seed)]);
+
+        assert_eq!(detect(&energy_only, &audio).len(), 1);
+        assert!(detect(&spectral, &audio).is_empty());
+
+        let audio = join(&[quiet(1000, &mut seed), tone(1000), quiet(1000, &mut seed)]);
+        assert_eq!(detect(&spectral, &audio).len(), 1);
+    }
+}
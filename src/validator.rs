@@ -0,0 +1,262 @@
+/// Checks of the commands in a profile that point at where each problem is
+///
+/// The profile file is read a second time with every value wrapped in [Spanned],
+/// so that problems can be reported with the line and column they are on.
+use crate::speech_to_text::normalize_prompt;
+use inputbot::{get_keybd_key, KeybdKey};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    ops::Range,
+};
+use toml::Spanned;
+
+#[derive(Deserialize)]
+struct SpannedProfile {
+    #[serde(default)]
+    commands: Vec<SpannedCommand>,
+}
+
+#[derive(Deserialize)]
+struct SpannedCommand {
+    name: Spanned<String>,
+    action: Spanned<String>,
+    modifiers: Option<Vec<Spanned<KeybdKey>>>,
+    #[serde(default)]
+    aliases: Vec<Spanned<String>>,
+    #[serde(default)]
+    translations: BTreeMap<String, Spanned<String>>,
+}
+
+impl SpannedCommand {
+    /// same order as `Command::names`
+    fn names(&self) -> impl Iterator<Item = &Spanned<String>> {
+        return std::iter::once(&self.name)
+            .chain(self.aliases.iter())
+            .chain(self.translations.values());
+    }
+}
+
+#[derive(Debug)]
+pub struct Problem {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        );
+    }
+}
+
+struct Validator<'a> {
+    contents: &'a str,
+    problems: Vec<Problem>,
+}
+
+impl Validator<'_> {
+    fn push(&mut self, span: Range<usize>, message: String) {
+        let (line, column) = self.position(span.start);
+        self.problems.push(Problem {
+            line,
+            column,
+            message,
+        });
+    }
+
+    /// 1-based line and column of a byte offset
+    fn position(&self, offset: usize) -> (usize, usize) {
+        let before = &self.contents[..offset.min(self.contents.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        return (line, column);
+    }
+}
+
+/// Checks every command of a profile file, returns all problems found\
+/// problems that stop the file from being read as a profile at all are left to deserializing it
+pub fn validate_commands(contents: &str) -> Vec<Problem> {
+    let profile: SpannedProfile = match toml::from_str(contents) {
+        Ok(x) => x,
+        Err(_) => return Vec::new(),
+    };
+    let mut validator = Validator {
+        contents,
+        problems: Vec::new(),
+    };
+
+    // the first command and name each normalized name was seen with
+    let mut claimed: HashMap<String, (usize, &Spanned<String>)> = HashMap::new();
+    for (index, command) in profile.commands.iter().enumerate() {
+        let command_name = command.name.get_ref();
+
+        for name in command.names() {
+            let processed = normalize_prompt(name.get_ref());
+            if processed.is_empty() {
+                validator.push(
+                    name.span(),
+                    format!(
+                        "'{}' has no letters or numbers, no transcript can match it",
+                        name.get_ref()
+                    ),
+                );
+                continue;
+            }
+            match claimed.get(&processed) {
+                None => {
+                    claimed.insert(processed, (index, name));
+                }
+                // e.g. an alias that only differs from the name in spacing
+                Some((other, _)) if *other == index => {}
+                Some((other, other_name)) => {
+                    let (line, _) = validator.position(other_name.span().start);
+                    let message = format!(
+                        "'{}' is already a name of command '{}' on line {}",
+                        name.get_ref(),
+                        profile.commands[*other].name.get_ref(),
+                        line
+                    );
+                    validator.push(name.span(), message);
+                }
+            }
+        }
+
+        let action = command.action.get_ref();
+        if action.is_empty() {
+            validator.push(
+                command.action.span(),
+                format!("command '{}' has an empty action", command_name),
+            );
+        }
+        let unknown: BTreeSet<char> = action
+            .chars()
+            .filter(|x| get_keybd_key(*x).is_none())
+            .collect();
+        if !unknown.is_empty() {
+            validator.push(
+                command.action.span(),
+                format!(
+                    "action of command '{}' has characters no key can send: {:?}",
+                    command_name, unknown
+                ),
+            );
+        }
+
+        let mut seen_modifiers = Vec::new();
+        for modifier in command.modifiers.iter().flatten() {
+            if seen_modifiers.contains(modifier.get_ref()) {
+                validator.push(
+                    modifier.span(),
+                    format!(
+                        "modifier {:?} is given twice for command '{}'",
+                        modifier.get_ref(),
+                        command_name
+                    ),
+                );
+            } else {
+                seen_modifiers.push(*modifier.get_ref());
+            }
+        }
+
+        for (language, name) in &command.translations {
+            if whisper_rs::get_lang_id(language).is_none() {
+                validator.push(
+                    name.span(),
+                    format!(
+                        "command '{}' has a translation for '{}', which is not a language whisper knows",
+                        command_name, language
+                    ),
+                );
+            }
+        }
+    }
+
+    return validator.problems;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILE: &str = r#"record_keybind = "numpad1"
+
+[whisper]
+
+[[commands]]
+name = "Resupply"
+action = "swsa"
+
+[[commands]]
+name = "re-supply"
+action = "wsdaw"
+
+[[commands]]
+name = "reinforce"
+action = ""
+modifiers = ["LeftControl", "LeftControl"]
+
+[[commands]]
+name = "?!"
+action = "sw€"
+"#;
+
+    fn problems(contents: &str) -> Vec<(usize, usize, String)> {
+        return validate_commands(contents)
+            .into_iter()
+            .map(|x| (x.line, x.column, x.message))
+            .collect();
+    }
+
+    #[test]
+    fn every_problem_has_its_position() {
+        let expected = [
+            (
+                10,
+                8,
+                "'re-supply' is already a name of command 'Resupply' on line 6",
+            ),
+            (15, 10, "command 'reinforce' has an empty action"),
+            (
+                16,
+                29,
+                "modifier LControlKey is given twice for command 'reinforce'",
+            ),
+            (
+                19,
+                8,
+                "'?!' has no letters or numbers, no transcript can match it",
+            ),
+            (
+                20,
+                10,
+                "action of command '?!' has characters no key can send: {'€'}",
+            ),
+        ];
+        let expected: Vec<(usize, usize, String)> = expected
+            .iter()
+            .map(|(line, column, message)| (*line, *column, message.to_string()))
+            .collect();
+        assert_eq!(problems(PROFILE), expected);
+    }
+
+    #[test]
+    fn valid_commands_have_no_problems() {
+        let contents = r#"record_keybind = "numpad1"
+
+[whisper]
+
+[[commands]]
+name = "eagle airstrike"
+action = "wdsd"
+aliases = ["air strike", "airstrike"]
+modifiers = ["LeftControl"]
+"#;
+        assert!(problems(contents).is_empty());
+    }
+}
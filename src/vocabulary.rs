@@ -0,0 +1,465 @@
+/// Closed vocabulary decoding
+///
+/// Command names are tokenized into a prefix tree. While whisper decodes, a logits filter
+/// looks up where the tokens decoded so far are in the tree and boosts (or only allows)
+/// the tokens that continue a command name. Grammar rules are not used as whisper-rs passes
+/// them to whisper.cpp as a flat array where it expects an array of rules.
+use crate::profiles::Whisper;
+use crate::speech_to_text::{current_model, normalize_prompt};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    ffi::c_void,
+    os::raw::c_int,
+};
+use whisper_rs::{
+    FullParams, WhisperContext, WhisperSysContext, WhisperSysState, WhisperTokenData,
+    WhisperTokenId,
+};
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClosedVocabulary {
+    /// decode freely
+    #[default]
+    Off,
+    /// make tokens that continue a command name more likely
+    Bias,
+    /// only allow tokens that continue a command name
+    Strict,
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<WhisperTokenId, usize>,
+    /// a whole command name ends here
+    terminal: bool,
+}
+
+/// Prefix tree of command name tokens, given to whisper as logits filter user data
+pub struct VocabularyTrie {
+    nodes: Vec<Node>,
+    mode: ClosedVocabulary,
+    bias: f32,
+    n_vocab: usize,
+    /// tokens from this one onwards are special (timestamps, language, ...)
+    token_eot: WhisperTokenId,
+    /// punctuation allowed to end a command in strict mode
+    end_tokens: Vec<WhisperTokenId>,
+}
+
+impl VocabularyTrie {
+    /// `conjunctions` may join several names in strict mode, "resupply and reinforce"
+    pub fn new(
+        ctx: &WhisperContext,
+        names: &[String],
+        conjunctions: &[String],
+        mode: ClosedVocabulary,
+        bias: f32,
+    ) -> Option<Self> {
+        if mode == ClosedVocabulary::Off || names.is_empty() {
+            return None;
+        }
+
+        let mut trie = Self {
+            nodes: vec![Node::default()],
+            mode,
+            bias,
+            n_vocab: ctx.n_vocab() as usize,
+            token_eot: ctx.token_eot(),
+            end_tokens: Vec::new(),
+        };
+        for punctuation in [".", "!", "?"] {
+            trie.end_tokens.extend(tokenize(ctx, punctuation));
+        }
+
+        for name in names {
+            // whisper usually starts with a space and may capitalize the first letter
+            let mut capitalized = name.chars();
+            let capitalized = match capitalized.next() {
+                None => continue,
+                Some(first) => first.to_uppercase().chain(capitalized).collect::<String>(),
+            };
+            for variant in [name.clone(), capitalized] {
+                trie.insert(&tokenize(ctx, &variant));
+                trie.insert(&tokenize(ctx, &format!(" {}", variant)));
+            }
+        }
+        // bias mode does not need them, whisper is free to say "and" there anyway
+        if mode == ClosedVocabulary::Strict {
+            for conjunction in conjunctions {
+                trie.link_to_root(&tokenize(ctx, conjunction));
+                trie.link_to_root(&tokenize(ctx, &format!(" {}", conjunction)));
+            }
+        }
+
+        debug!(
+            "[VOCABULARY] {} command names became {} token nodes",
+            names.len(),
+            trie.nodes.len()
+        );
+        return Some(trie);
+    }
+
+    fn insert(&mut self, tokens: &[WhisperTokenId]) {
+        if tokens.is_empty() {
+            return;
+        }
+
+        let mut current = 0;
+        for token in tokens {
+            current = match self.nodes[current].children.get(token) {
+                Some(x) => *x,
+                None => {
+                    self.nodes.push(Node::default());
+                    let index = self.nodes.len() - 1;
+                    self.nodes[current].children.insert(*token, index);
+                    index
+                }
+            };
+        }
+        self.nodes[current].terminal = true;
+    }
+
+    /// Lets `tokens` follow every whole name and lead back to the root, so that another name
+    /// can start after them. Names win over the link when a name continues with the same token
+    fn link_to_root(&mut self, tokens: &[WhisperTokenId]) {
+        let (last, path) = match tokens.split_last() {
+            None => return,
+            Some(x) => x,
+        };
+
+        let terminals: Vec<usize> = (0..self.nodes.len())
+            .filter(|x| self.nodes[*x].terminal)
+            .collect();
+        for terminal in terminals {
+            if self.nodes[terminal].children.contains_key(&tokens[0]) {
+                continue;
+            }
+            let mut current = terminal;
+            for token in path {
+                self.nodes.push(Node::default());
+                let index = self.nodes.len() - 1;
+                self.nodes[current].children.insert(*token, index);
+                current = index;
+            }
+            self.nodes[current].children.insert(*last, 0);
+        }
+    }
+
+    /// node reached by the text tokens in `decoded`, `None` when they left the tree
+    fn find(&self, decoded: impl IntoIterator<Item = WhisperTokenId>) -> Option<&Node> {
+        let mut current = 0;
+        for token in decoded.into_iter().filter(|x| *x < self.token_eot) {
+            current = *self.nodes[current].children.get(&token)?;
+        }
+        return Some(&self.nodes[current]);
+    }
+
+    /// `decoded` are the ids of the tokens decoded so far
+    fn filter(&self, decoded: impl IntoIterator<Item = WhisperTokenId>, logits: &mut [f32]) {
+        let text_tokens = (self.token_eot as usize).min(logits.len());
+        let node = match self.find(decoded) {
+            // a finished command was followed by punctuation, nothing else may follow
+            None if self.mode == ClosedVocabulary::Strict => {
+                for (token, logit) in logits.iter_mut().enumerate() {
+                    if token != self.token_eot as usize {
+                        *logit = f32::NEG_INFINITY;
+                    }
+                }
+                return;
+            }
+            // bias only nudges, whisper left the vocabulary on its own
+            None => return,
+            Some(x) => x,
+        };
+
+        match self.mode {
+            ClosedVocabulary::Off => {}
+            ClosedVocabulary::Bias => {
+                for token in node.children.keys() {
+                    logits[*token as usize] += self.bias;
+                }
+            }
+            ClosedVocabulary::Strict => {
+                let mut allowed = vec![false; text_tokens];
+                for token in node.children.keys() {
+                    allowed[*token as usize] = true;
+                }
+                if node.terminal {
+                    for token in &self.end_tokens {
+                        allowed[*token as usize] = true;
+                    }
+                } else if let Some(eot) = logits.get_mut(self.token_eot as usize) {
+                    // half a command name is not allowed to end the transcript
+                    *eot = f32::NEG_INFINITY;
+                }
+
+                for (token, logit) in logits[..text_tokens].iter_mut().enumerate() {
+                    if !allowed[token] {
+                        *logit = f32::NEG_INFINITY;
+                    }
+                }
+            }
+        }
+    }
+
+    /// `self` has to outlive every use of `params`
+    pub fn apply(&self, params: &mut FullParams) {
+        unsafe {
+            params.set_filter_logits_callback(Some(filter_logits));
+            params.set_filter_logits_callback_user_data(self as *const Self as *mut c_void);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Builds the initial prompt, a "Glossary:" of the unique command names after
+/// `initial_prompt`. Whisper only looks at the last `n_text_ctx / 2` tokens of a prompt,
+/// when the glossary does not fit then the names that take the fewest tokens are left out,
+/// as those are the words whisper already knows best
+pub fn build_prompt(whisper: &Whisper) -> String {
+    if let Some(prompt) = &whisper.prompt_override {
+        return prompt.clone();
+    }
+
+    let mut seen = HashSet::new();
+    let terms: Vec<&str> = whisper
+        .vocabulary
+        .iter()
+        .filter(|x| seen.insert(normalize_prompt(x)))
+        .map(String::as_str)
+        .collect();
+    let prompt = compose_prompt(&whisper.initial_prompt, &terms);
+
+    let model = match current_model() {
+        None => {
+            debug!("[PROMPT] no model loaded, prompt length is not checked");
+            return prompt;
+        }
+        Some(x) => x,
+    };
+    let ctx = model.ctx();
+    let budget = (ctx.n_text_ctx() / 2 - 1).max(0) as usize;
+    let length = tokenize(ctx, &prompt).len();
+    if length <= budget {
+        info!("[PROMPT] prompt takes {} of {} tokens", length, budget);
+        return prompt;
+    }
+
+    let base_prompt = format!("{} Glossary:", whisper.initial_prompt.trim());
+    let base_length = tokenize(ctx, &base_prompt).len();
+    if base_length >= budget {
+        warn!(
+            "[PROMPT] initial_prompt alone takes {} of {} tokens, leaving out the glossary",
+            base_length, budget
+        );
+        return whisper.initial_prompt.clone();
+    }
+
+    // what each name adds to the glossary, including the separator
+    let costs: Vec<usize> = terms
+        .iter()
+        .map(|x| tokenize(ctx, &format!(", {}", x)).len())
+        .collect();
+    let mut by_cost: Vec<usize> = (0..terms.len()).collect();
+    by_cost.sort_by_key(|x| Reverse(costs[*x]));
+
+    let mut remaining = budget - base_length;
+    let mut keep = vec![false; terms.len()];
+    for index in by_cost {
+        if costs[index] <= remaining {
+            remaining -= costs[index];
+            keep[index] = true;
+        }
+    }
+
+    let dropped: Vec<&str> = (0..terms.len())
+        .filter(|x| !keep[*x])
+        .map(|x| terms[x])
+        .collect();
+    warn!(
+        "[PROMPT] prompt takes {} of {} tokens, left out of the glossary: {}",
+        length,
+        budget,
+        dropped.join(", ")
+    );
+    let kept: Vec<&str> = (0..terms.len())
+        .filter(|x| keep[*x])
+        .map(|x| terms[x])
+        .collect();
+    return compose_prompt(&whisper.initial_prompt, &kept);
+}
+
+fn compose_prompt(initial_prompt: &str, terms: &[&str]) -> String {
+    let initial_prompt = initial_prompt.trim();
+    if terms.is_empty() {
+        return initial_prompt.to_string();
+    }
+
+    let glossary = format!("Glossary: {}", terms.join(", "));
+    if initial_prompt.is_empty() {
+        return glossary;
+    }
+    return format!("{} {}", initial_prompt, glossary);
+}
+
+fn tokenize(ctx: &WhisperContext, text: &str) -> Vec<WhisperTokenId> {
+    return match ctx.tokenize(text, text.len() + 8) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("[VOCABULARY] could not tokenize '{}': {:?}", text, e);
+            Vec::new()
+        }
+    };
+}
+
+/// called by every whisper decoder, possibly at the same time, so the trie is only read
+unsafe extern "C" fn filter_logits(
+    _ctx: *mut WhisperSysContext,
+    _state: *mut WhisperSysState,
+    tokens: *const WhisperTokenData,
+    n_tokens: c_int,
+    logits: *mut f32,
+    user_data: *mut c_void,
+) {
+    if user_data.is_null() || logits.is_null() {
+        return;
+    }
+
+    let trie = &*(user_data as *const VocabularyTrie);
+    let decoded = if tokens.is_null() || n_tokens <= 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(tokens, n_tokens as usize)
+    };
+    let logits = std::slice::from_raw_parts_mut(logits, trie.n_vocab);
+    trie.filter(decoded.iter().map(|x| x.id), logits);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const N_VOCAB: usize = 11;
+    const TOKEN_EOT: WhisperTokenId = 9;
+    /// "."
+    const END: WhisperTokenId = 8;
+    /// a timestamp, special like every token after end of text
+    const TIMESTAMP: WhisperTokenId = 10;
+
+    /// " and" joins two names
+    const AND: WhisperTokenId = 6;
+    /// "," joins two names
+    const COMMA: WhisperTokenId = 7;
+
+    /// "orbital laser" is tokens 1 2, "orbital" alone 1 3 and "resupply" 4\
+    /// the conjunctions lead back to the root
+    fn trie(mode: ClosedVocabulary) -> VocabularyTrie {
+        let mut trie = VocabularyTrie {
+            nodes: vec![Node::default()],
+            mode,
+            bias: 5.0,
+            n_vocab: N_VOCAB,
+            token_eot: TOKEN_EOT,
+            end_tokens: vec![END],
+        };
+        trie.insert(&[1, 2]);
+        trie.insert(&[1, 3]);
+        trie.insert(&[4]);
+        trie.link_to_root(&[AND]);
+        trie.link_to_root(&[COMMA]);
+        return trie;
+    }
+
+    /// tokens whose logit is not -inf after filtering
+    fn allowed(trie: &VocabularyTrie, decoded: &[WhisperTokenId]) -> Vec<WhisperTokenId> {
+        let mut logits = vec![0.0; N_VOCAB];
+        trie.filter(decoded.iter().copied(), &mut logits);
+        return (0..N_VOCAB as WhisperTokenId)
+            .filter(|x| logits[*x as usize].is_finite())
+            .collect();
+    }
+
+    #[test]
+    fn strict_only_continues_names() {
+        let trie = trie(ClosedVocabulary::Strict);
+        assert_eq!(allowed(&trie, &[]), [1, 4, TIMESTAMP]);
+        assert_eq!(allowed(&trie, &[1]), [2, 3, TIMESTAMP]);
+        // special tokens in between do not move through the tree
+        assert_eq!(allowed(&trie, &[TIMESTAMP, 1]), [2, 3, TIMESTAMP]);
+    }
+
+    #[test]
+    fn strict_ends_after_a_name() {
+        let trie = trie(ClosedVocabulary::Strict);
+        assert_eq!(
+            allowed(&trie, &[1, 2]),
+            [AND, COMMA, END, TOKEN_EOT, TIMESTAMP]
+        );
+        assert_eq!(
+            allowed(&trie, &[4]),
+            [AND, COMMA, END, TOKEN_EOT, TIMESTAMP]
+        );
+    }
+
+    #[test]
+    fn strict_allows_several_names_joined_by_conjunctions() {
+        let trie = trie(ClosedVocabulary::Strict);
+        // "resupply and orbital laser, resupply."
+        let sequence = [4, AND, 1, 2, COMMA, 4, END];
+        for length in 0..sequence.len() {
+            let next = sequence[length];
+            assert!(
+                allowed(&trie, &sequence[..length]).contains(&next),
+                "{:?} can not follow {:?}",
+                next,
+                &sequence[..length]
+            );
+        }
+        assert_eq!(allowed(&trie, &sequence), [TOKEN_EOT]);
+
+        // a conjunction has to be followed by another name
+        assert_eq!(allowed(&trie, &[4, AND]), [1, 4, TIMESTAMP]);
+        // and only follows whole names
+        assert!(!allowed(&trie, &[1]).contains(&AND));
+    }
+
+    #[test]
+    fn names_win_over_conjunction_links() {
+        let mut trie = trie(ClosedVocabulary::Strict);
+        // "orbital" alone would also be a name, "orbital, laser" continues it
+        trie.insert(&[1]);
+        trie.insert(&[1, COMMA, 5]);
+        trie.link_to_root(&[COMMA]);
+        assert_eq!(allowed(&trie, &[1, COMMA]), [5, TIMESTAMP]);
+    }
+
+    #[test]
+    fn strict_forces_end_of_text_outside_the_tree() {
+        let trie = trie(ClosedVocabulary::Strict);
+        assert_eq!(allowed(&trie, &[1, 2, END]), [TOKEN_EOT]);
+        assert_eq!(allowed(&trie, &[4, END, TIMESTAMP]), [TOKEN_EOT]);
+        assert_eq!(allowed(&trie, &[5]), [TOKEN_EOT]);
+    }
+
+    #[test]
+    fn bias_boosts_continuations() {
+        let trie = trie(ClosedVocabulary::Bias);
+
+        let mut logits = vec![0.0; N_VOCAB];
+        trie.filter([1], &mut logits);
+        for (token, logit) in logits.iter().enumerate() {
+            let expected = if token == 2 || token == 3 { 5.0 } else { 0.0 };
+            assert_eq!(*logit, expected, "token {}", token);
+        }
+
+        // outside the tree nothing changes
+        let mut logits = vec![0.0; N_VOCAB];
+        trie.filter([5], &mut logits);
+        assert!(logits.iter().all(|x| *x == 0.0));
+    }
+}
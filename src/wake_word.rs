@@ -0,0 +1,171 @@
+/// Wake word gating for the hands-free (open mic) mode
+use crate::speech_to_text::normalize_prompt;
+use log::{debug, info};
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct WakeWord {
+    /// utterances have to start with this to be matched against commands, e.g. "strike"
+    pub phrase: String,
+    /// after the wake word was heard, commands within this many milliseconds do not need it
+    #[serde(default)]
+    pub follow_up_ms: Option<u64>,
+}
+
+impl WakeWord {
+    /// Checks that the settings make sense, returns every problem found
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if normalize_prompt(&self.phrase).is_empty() {
+            problems.push("wake_word.phrase must contain letters or numbers".to_string());
+        }
+        return problems;
+    }
+}
+
+pub struct WakeWordGate {
+    /// normalized the same way as transcripts
+    phrase: String,
+    follow_up: Option<Duration>,
+    last_activation: Option<Instant>,
+}
+
+impl WakeWordGate {
+    pub fn new(wake_word: &WakeWord) -> Self {
+        return Self {
+            phrase: normalize_prompt(&wake_word.phrase),
+            follow_up: wake_word.follow_up_ms.map(Duration::from_millis),
+            last_activation: None,
+        };
+    }
+
+    /// Takes a transcript and returns it without the wake word,
+    /// `None` when the utterance should be ignored\
+    /// `starts_command` tells whether a normalized word begins a command, see
+    /// [crate::profiles::Config::starts_command]
+    pub fn check(&mut self, text: &str, starts_command: impl Fn(&str) -> bool) -> Option<String> {
+        let now = Instant::now();
+
+        if let Some(rest) = self.strip_phrase(text, starts_command) {
+            debug!("[WAKE WORD] heard wake word");
+            self.last_activation = Some(now);
+            // only the wake word was said, nothing to match
+            if normalize_prompt(&rest).is_empty() {
+                return None;
+            }
+            return Some(rest);
+        }
+
+        let in_follow_up = match (self.follow_up, self.last_activation) {
+            (Some(follow_up), Some(last)) => now.duration_since(last) <= follow_up,
+            _ => false,
+        };
+        if in_follow_up {
+            debug!("[WAKE WORD] follow-up command, wake word not needed");
+            self.last_activation = Some(now);
+            return Some(text.to_string());
+        }
+
+        info!("[WAKE WORD] ignoring '{}'", text);
+        return None;
+    }
+
+    /// `text` without the leading words that make up the wake word, the rest keeps its
+    /// spaces and punctuation so that several commands in it can still be told apart
+    fn strip_phrase(&self, text: &str, starts_command: impl Fn(&str) -> bool) -> Option<String> {
+        let mut heard = String::new();
+        let mut words = text.split_whitespace();
+        while heard.len() < self.phrase.len() {
+            match words.next() {
+                None => break,
+                Some(word) => heard.push_str(&normalize_prompt(word)),
+            }
+        }
+        if heard == self.phrase {
+            return Some(words.collect::<Vec<_>>().join(" "));
+        }
+
+        // whisper sometimes writes the wake word and the command as one word,
+        // "strikeresupply" is glued but "strikes" is just another word
+        let mut words = text.split_whitespace();
+        let first = normalize_prompt(words.next()?);
+        let rest = first.strip_prefix(&self.phrase)?;
+        if !starts_command(rest) {
+            return None;
+        }
+        return Some(
+            std::iter::once(rest)
+                .chain(words)
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wake_word(phrase: &str, follow_up_ms: Option<u64>) -> WakeWord {
+        return WakeWord {
+            phrase: phrase.to_string(),
+            follow_up_ms,
+        };
+    }
+
+    #[test]
+    fn phrase_needs_letters_or_numbers() {
+        assert!(wake_word("Hey Strike", None).validate().is_empty());
+        for phrase in ["", " ", "?!"] {
+            assert_eq!(wake_word(phrase, None).validate().len(), 1, "'{}'", phrase);
+        }
+    }
+
+    /// stands in for [crate::profiles::Config::starts_command]
+    fn starts_command(word: &str) -> bool {
+        return ["resupply", "reinforce", "orbital", "orbitallaser"].contains(&word);
+    }
+
+    #[test]
+    fn strips_leading_words() {
+        let mut gate = WakeWordGate::new(&wake_word("Hey Strike", None));
+        assert_eq!(
+            gate.check("Hey, strike! Resupply and reinforce.", starts_command),
+            Some("Resupply and reinforce.".to_string())
+        );
+        // only the wake word was said
+        assert_eq!(gate.check("Hey strike.", starts_command), None);
+        assert_eq!(gate.check("Resupply and reinforce.", starts_command), None);
+    }
+
+    #[test]
+    fn strips_phrase_glued_to_first_word() {
+        let mut gate = WakeWordGate::new(&wake_word("strike", None));
+        assert_eq!(
+            gate.check("Strikeresupply and reinforce", starts_command),
+            Some("resupply and reinforce".to_string())
+        );
+        assert_eq!(
+            gate.check("Strikeorbital laser", starts_command),
+            Some("orbital laser".to_string())
+        );
+        // the rest of the word has to begin a command
+        assert_eq!(
+            gate.check("Strikes resupply and reinforce", starts_command),
+            None
+        );
+        assert_eq!(gate.check("Resupply strike", starts_command), None);
+    }
+
+    #[test]
+    fn follow_up_does_not_need_wake_word() {
+        let mut gate = WakeWordGate::new(&wake_word("strike", Some(60_000)));
+        assert_eq!(gate.check("resupply", starts_command), None);
+        assert_eq!(gate.check("strike", starts_command), None);
+        assert_eq!(
+            gate.check("resupply", starts_command),
+            Some("resupply".to_string())
+        );
+    }
+}